@@ -0,0 +1,44 @@
+use std::thread;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use kv::kv::{builder::Durability, KvStore};
+use tempfile::TempDir;
+
+// 写线程数和每个线程写入的次数
+const THREADS: usize = 8;
+const WRITES_PER_THREAD: usize = 50;
+
+// 多个线程同时写，每次写完都fsync，对比组提交和逐条提交的吞吐量
+fn concurrent_set(c: &mut Criterion) {
+  let mut group = c.benchmark_group("concurrent_set_fsync_always");
+  group.throughput(Throughput::Elements((THREADS * WRITES_PER_THREAD) as u64));
+  group.sample_size(10);
+  for group_commit in [false, true] {
+    let name = if group_commit { "group_commit" } else { "one_by_one" };
+    group.bench_with_input(BenchmarkId::from_parameter(name), &group_commit, |b, &group_commit| {
+      let dir = TempDir::new().unwrap();
+      let store = KvStore::builder(dir.path())
+        .durability(Durability::Always)
+        .group_commit(group_commit)
+        .compaction_threshold(u64::MAX)
+        .open()
+        .unwrap();
+      b.iter(|| {
+        thread::scope(|scope| {
+          for t in 0..THREADS {
+            let store = store.clone();
+            scope.spawn(move || {
+              for i in 0..WRITES_PER_THREAD {
+                store.set(format!("key-{}-{}", t, i), format!("value-{}", i)).unwrap();
+              }
+            });
+          }
+        });
+      });
+    });
+  }
+  group.finish();
+}
+
+criterion_group!(benches, concurrent_set);
+criterion_main!(benches);
@@ -0,0 +1,143 @@
+use std::{io::{Error, ErrorKind}, time::Duration};
+
+use tokio::{io::{AsyncWriteExt, BufWriter}, net::{tcp::{OwnedReadHalf, OwnedWriteHalf}, TcpStream, ToSocketAddrs}};
+
+use crate::{async_io::JsonReader, error::{KvError, Result}, kv::{batch::WriteBatch, command::Command}, req::{Body, Request, Response, ScanPage}};
+
+/// 异步的客户端，一个连接可以连续发送多个请求
+pub struct AsyncKvClient {
+  writer: BufWriter<OwnedWriteHalf>,
+  reader: JsonReader<OwnedReadHalf>,
+}
+
+impl AsyncKvClient {
+  pub async fn connect(addr: impl ToSocketAddrs) -> Result<AsyncKvClient> {
+    let (reader, writer) = TcpStream::connect(addr).await?.into_split();
+    Ok(AsyncKvClient {
+      writer: BufWriter::new(writer),
+      reader: JsonReader::new(reader),
+    })
+  }
+
+  pub async fn get(&mut self, key: String) -> Result<Option<String>> {
+    self.send(Command::Get { key }).await
+  }
+
+  pub async fn set(&mut self, key: String, value: String) -> Result<()> {
+    self.send(Command::set(key, value)).await.map(|_| ())
+  }
+
+  /// set，ttl之后过期
+  pub async fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+    self.send(Command::Set { key, value, ttl: Some(ttl.as_millis() as u64), expires_at: None, version: None }).await.map(|_| ())
+  }
+
+  /// 根据key取值和它的版本号，key不存在时返回`None`
+  pub async fn get_versioned(&mut self, key: String) -> Result<Option<(String, u64)>> {
+    let resp = self.request(Command::GetVersioned { key }).await?;
+    match (resp.result.map_err(KvError::from)?, resp.body) {
+      (Some(value), Some(Body::Version(version))) => Ok(Some((value, version))),
+      (None, _) => Ok(None),
+      _ => Err(KvError::Protocol("get-versioned的响应格式不对".to_string())),
+    }
+  }
+
+  /// key不存在时才set，返回新的版本号
+  pub async fn set_if_absent(&mut self, key: String, value: String) -> Result<u64> {
+    self.send_conditional(Command::SetIfAbsent { key, value }).await
+  }
+
+  /// key当前的值等于expected时才set，返回新的版本号
+  pub async fn set_if_equals(&mut self, key: String, expected: String, value: String) -> Result<u64> {
+    self.send_conditional(Command::SetIfEquals { key, value, expected }).await
+  }
+
+  /// key当前的版本号等于version时才set，返回新的版本号
+  pub async fn set_if_version(&mut self, key: String, version: u64, value: String) -> Result<u64> {
+    self.send_conditional(Command::SetIfVersion { key, value, version }).await
+  }
+
+  pub async fn remove(&mut self, key: String) -> Result<()> {
+    self.send(Command::Remove { key }).await.map(|_| ())
+  }
+
+  /// 原子写入一组set和remove，要么全部生效，要么都不生效
+  pub async fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+    self.send(Command::Batch { ops: batch.into_commands() }).await.map(|_| ())
+  }
+
+  pub async fn compact(&mut self) -> Result<()> {
+    self.send(Command::Compact).await.map(|_| ())
+  }
+
+  /// 取一页[start, end)范围内的键值对，用法和[`KvClient::scan`](crate::client::KvClient::scan)一样
+  pub async fn scan(&mut self, start: Option<String>, end: Option<String>, limit: Option<usize>, cursor: Option<String>) -> Result<ScanPage> {
+    match self.request(Command::Scan { start, end, limit, cursor }).await?.into_body()? {
+      Body::Scan(page) => Ok(page),
+      _ => Err(KvError::Protocol("scan的响应格式不对".to_string())),
+    }
+  }
+
+  /// 按顺序返回以prefix开头的所有key
+  pub async fn keys(&mut self, prefix: String) -> Result<Vec<String>> {
+    let mut keys = Vec::new();
+    self.for_each_key(prefix, |key| keys.push(key)).await?;
+    Ok(keys)
+  }
+
+  /// 按顺序处理以prefix开头的每个key，用法和[`KvClient::for_each_key`](crate::client::KvClient::for_each_key)一样
+  pub async fn for_each_key(&mut self, prefix: String, mut f: impl FnMut(String)) -> Result<()> {
+    self.write_request(Command::Keys { prefix }).await?;
+    loop {
+      match self.read_response().await?.into_body()? {
+        Body::Keys { keys, more } => {
+          keys.into_iter().for_each(&mut f);
+          if !more {
+            return Ok(());
+          }
+        },
+        _ => return Err(KvError::Protocol("keys的响应格式不对".to_string())),
+      }
+    }
+  }
+
+  /// 以prefix开头的key的数量
+  pub async fn count(&mut self, prefix: String) -> Result<usize> {
+    match self.request(Command::Count { prefix }).await?.into_body()? {
+      Body::Count(count) => Ok(count),
+      _ => Err(KvError::Protocol("count的响应格式不对".to_string())),
+    }
+  }
+
+  // 发送请求，等待响应，服务端返回的错误按错误码转成对应的错误
+  async fn send(&mut self, command: Command) -> Result<Option<String>> {
+    self.request(command).await?.result.map_err(KvError::from)
+  }
+
+  // 发送条件写的请求，返回新的版本号
+  async fn send_conditional(&mut self, command: Command) -> Result<u64> {
+    match self.request(command).await?.into_body()? {
+      Body::Version(version) => Ok(version),
+      _ => Err(KvError::Protocol("条件写的响应格式不对".to_string())),
+    }
+  }
+
+  // 发送请求，等待响应
+  async fn request(&mut self, command: Command) -> Result<Response> {
+    self.write_request(command).await?;
+    self.read_response().await
+  }
+
+  async fn write_request(&mut self, command: Command) -> Result<()> {
+    self.writer.write_all(&serde_json::to_vec(&Request { command })?).await?;
+    self.writer.flush().await?;
+    Ok(())
+  }
+
+  async fn read_response(&mut self) -> Result<Response> {
+    self.reader
+      .next::<Response>()
+      .await?
+      .ok_or_else(|| KvError::Io(Error::from(ErrorKind::UnexpectedEof)))
+  }
+}
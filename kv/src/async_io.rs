@@ -0,0 +1,77 @@
+use std::io::{Error, ErrorKind};
+
+use serde::de::DeserializeOwned;
+use serde_json::Deserializer;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::error::{KvError, Result};
+
+/// 从异步流中一个一个读出json对象
+///
+/// 协议和阻塞版本一样，json对象直接首尾相连，没有分隔符，所以读到的数据先放进缓冲区，
+/// 能解析出一个完整的对象就返回，不完整就接着读。
+pub(crate) struct JsonReader<R> {
+  reader: R,
+  // 已经读到但还没有解析的数据
+  buf: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> JsonReader<R> {
+  pub fn new(reader: R) -> JsonReader<R> {
+    JsonReader { reader, buf: Vec::new() }
+  }
+
+  /// 读下一个对象，流正常结束时返回`None`，数据不是要的格式返回[`KvError::Protocol`]
+  pub async fn next<T: DeserializeOwned>(&mut self) -> Result<Option<T>> {
+    let mut chunk = [0; 4096];
+    loop {
+      let mut stream = Deserializer::from_slice(&self.buf).into_iter::<T>();
+      match stream.next() {
+        Some(Ok(value)) => {
+          // 去掉已经解析过的数据
+          let offset = stream.byte_offset();
+          self.buf.drain(..offset);
+          return Ok(Some(value));
+        },
+        // 数据还不完整，接着读
+        Some(Err(e)) if e.is_eof() => (),
+        Some(Err(e)) => return Err(KvError::Protocol(e.to_string())),
+        // 缓冲区中只有空白字符
+        None => (),
+      }
+      let n = self.reader.read(&mut chunk).await?;
+      if n == 0 {
+        return if self.buf.iter().all(u8::is_ascii_whitespace) {
+          Ok(None)
+        } else {
+          Err(KvError::Io(Error::from(ErrorKind::UnexpectedEof)))
+        };
+      }
+      self.buf.extend_from_slice(&chunk[..n]);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::{error::Result, kv::command::Command, req::Request};
+
+  use super::JsonReader;
+
+  #[tokio::test]
+  async fn test_next() -> Result<()> {
+    let mut data = Vec::new();
+    for key in ["foo", "bar"] {
+      serde_json::to_writer(&mut data, &Request { command: Command::Get { key: key.to_string() } })?;
+    }
+    // 每次只给一个字节，模拟数据分多次到达
+    let reader = tokio::io::BufReader::with_capacity(1, data.as_slice());
+    let mut reader = JsonReader::new(reader);
+    for key in ["foo", "bar"] {
+      let request = reader.next::<Request>().await?.expect("没有读到请求");
+      assert!(matches!(request.command, Command::Get { key: k } if k == key));
+    }
+    assert!(reader.next::<Request>().await?.is_none());
+    Ok(())
+  }
+}
@@ -0,0 +1,139 @@
+use std::{io, net::SocketAddr};
+
+use tokio::{io::{AsyncWriteExt, BufWriter}, net::{TcpListener, TcpStream}, sync::mpsc, task};
+
+use crate::{async_io::JsonReader, engine::KvsEngine, error::{KvError, Result}, req::{Request, Response}, server::{execute, ServerConfig}};
+
+/// 基于tokio的异步服务，请求和响应的格式和[`KvServer`](crate::server::KvServer)一样
+///
+/// 存储引擎的操作是阻塞的，放到tokio的阻塞线程池中执行，不会卡住异步的工作线程。
+/// 配置和[`KvServer`](crate::server::KvServer)共用，工作线程由tokio的运行时决定，不用配置中的线程数。
+// 阻塞线程池发回响应的channel中最多缓存的响应数量，写得慢时阻塞线程会等着，不会一直取数据
+const RESPONSE_BUFFER: usize = 4;
+
+pub struct AsyncKvServer<E: KvsEngine> {
+  store: E,
+  // 监听的地址
+  addr: SocketAddr,
+}
+
+impl<E: KvsEngine> AsyncKvServer<E> {
+  pub fn new(store: E, config: ServerConfig) -> AsyncKvServer<E> {
+    AsyncKvServer { store, addr: config.addr }
+  }
+
+  pub async fn start(&self) -> Result<()> {
+    self.serve(TcpListener::bind(self.addr).await?).await
+  }
+
+  /// 在已经绑定好的listener上处理请求，每个连接一个异步任务
+  pub async fn serve(&self, tcp_listener: TcpListener) -> Result<()> {
+    loop {
+      match tcp_listener.accept().await {
+        Ok((stream, _)) => {
+          let store = self.store.clone();
+          tokio::spawn(async move {
+            if let Err(e) = handle_connection(store, stream).await {
+              println!("请求错误！{}", e);
+            }
+          });
+        },
+        Err(e) => println!("网络连接错误！{}", e),
+      }
+    }
+  }
+}
+
+async fn handle_connection<E: KvsEngine>(store: E, stream: TcpStream) -> Result<()> {
+  let peer_addr = stream.peer_addr()?;
+  println!("from: {}", peer_addr);
+
+  let (reader, writer) = stream.into_split();
+  let mut reader = JsonReader::new(reader);
+  let mut writer = BufWriter::new(writer);
+
+  loop {
+    match reader.next::<Request>().await {
+      // 响应通过channel一个个发回来，一产生就写出去，keys的结果不用都准备好了再发
+      Ok(Some(reqeust)) => {
+        println!("command: {}", serde_json::to_string(&reqeust.command)?);
+        let store = store.clone();
+        let (sender, mut receiver) = mpsc::channel(RESPONSE_BUFFER);
+        let executing = task::spawn_blocking(move || {
+          // 连接的写端出错退出后channel就关闭了，不用再取数据
+          execute(&store, reqeust.command, |response| sender.blocking_send(response).map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe).into()))
+        });
+        while let Some(response) = receiver.recv().await {
+          writer.write_all(&serde_json::to_vec(&response)?).await?;
+        }
+        executing.await.map_err(io::Error::from)??;
+      },
+      Ok(None) => break,
+      // 请求的格式不对，后面的数据也没法解析了，返回错误后关闭连接
+      Err(e @ KvError::Protocol(_)) => {
+        let response = Response::error(e);
+        writer.write_all(&serde_json::to_vec(&response)?).await?;
+        writer.flush().await?;
+        break;
+      },
+      Err(e) => return Err(e),
+    }
+    writer.flush().await?;
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use tokio::net::TcpListener;
+
+  use crate::{async_client::AsyncKvClient, engine::KvsEngine, error::{KvError, Result}, kv::memory::MemoryStore, server::{ServerConfig, KEYS_CHUNK_SIZE}};
+
+  use super::AsyncKvServer;
+
+  #[tokio::test]
+  async fn test_async_set_get_remove() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move { AsyncKvServer::new(MemoryStore::new(), ServerConfig::default()).serve(listener).await });
+
+    let mut client = AsyncKvClient::connect(addr).await?;
+    client.set("key".to_string(), "value".to_string()).await?;
+    assert_eq!(Some("value".to_string()), client.get("key".to_string()).await?);
+    client.remove("key".to_string()).await?;
+    assert_eq!(None, client.get("key".to_string()).await?);
+    assert!(matches!(client.remove("key".to_string()).await, Err(KvError::KeyNotFound)));
+
+    client.set("a/1".to_string(), "value".to_string()).await?;
+    client.set("a/2".to_string(), "value".to_string()).await?;
+    client.set("b/1".to_string(), "value".to_string()).await?;
+    assert_eq!(vec!["a/1".to_string(), "a/2".to_string()], client.keys("a/".to_string()).await?);
+    let mut count = 0;
+    client.for_each_key("b/".to_string(), |_| count += 1).await?;
+    assert_eq!(1, count);
+    assert_eq!(3, client.count(String::new()).await?);
+
+    let version = client.set_if_absent("cas".to_string(), "1".to_string()).await?;
+    assert!(matches!(client.set_if_absent("cas".to_string(), "2".to_string()).await, Err(KvError::ConditionFailed)));
+    assert_eq!(Some(("1".to_string(), version)), client.get_versioned("cas".to_string()).await?);
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_async_keys_in_chunks() -> Result<()> {
+    let store = MemoryStore::new();
+    let keys: Vec<String> = (0..KEYS_CHUNK_SIZE * 2 + 1).map(|i| format!("key-{:05}", i)).collect();
+    for key in &keys {
+      store.set(key.clone(), "value".to_string())?;
+    }
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move { AsyncKvServer::new(store, ServerConfig::default()).serve(listener).await });
+
+    // 分成多个响应发送，收到的key和顺序都不变，之后还能接着发请求
+    let mut client = AsyncKvClient::connect(addr).await?;
+    assert_eq!(keys, client.keys(String::new()).await?);
+    assert_eq!(keys.len(), client.count(String::new()).await?);
+    Ok(())
+  }
+}
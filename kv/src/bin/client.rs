@@ -0,0 +1,71 @@
+use std::{process::exit, time::Duration};
+
+use clap::Parser;
+use kv::{client::KvClient, error::{KvError, Result}, kv::command::{Cli, Command}};
+
+const DEFAULT_SERVER_PORT: &str = "127.0.0.1:4000";
+
+fn main() {
+  let parse = Cli::parse();
+
+  let port = parse
+    .port
+    .unwrap_or(String::from(DEFAULT_SERVER_PORT));
+
+  let mut client = KvClient::connect(port).unwrap_or_else(|e| fail(e));
+
+  let result = match parse.command {
+    Command::Set { key, value, ttl: Some(ttl), .. } => client.set_with_ttl(key, value, Duration::from_millis(ttl)).map(|_| None),
+    Command::Set { key, value, .. } => client.set(key, value).map(|_| None),
+    // key不存在时按KeyNotFound处理：错误信息写到stderr，退出码是3，和值就是"Key not found"的情况区分开
+    Command::Get { key } => client.get(key).and_then(|value| value.ok_or(KvError::KeyNotFound).map(Some)),
+    Command::GetVersioned { key } => client
+      .get_versioned(key)
+      .and_then(|value| value.ok_or(KvError::KeyNotFound))
+      .map(|(value, version)| Some(format!("{}\t{}", value, version))),
+    Command::SetIfAbsent { key, value } => client.set_if_absent(key, value).map(|version| Some(version.to_string())),
+    Command::SetIfEquals { key, value, expected } => client.set_if_equals(key, expected, value).map(|version| Some(version.to_string())),
+    Command::SetIfVersion { key, value, version } => client.set_if_version(key, version, value).map(|version| Some(version.to_string())),
+    Command::Remove { key } => client.remove(key).map(|_| None),
+    Command::Compact => client.compact().map(|_| None),
+    Command::Scan { start, end, limit, .. } => scan(&mut client, start, end, limit).map(|_| None),
+    Command::Keys { prefix } => client.for_each_key(prefix, |key| println!("{}", key)).map(|_| None),
+    Command::Count { prefix } => client.count(prefix).map(|count| Some(count.to_string())),
+    // Batch是#[command(skip)]的，只在协议中使用，命令行解析不出来
+    Command::Batch { .. } => unreachable!("命令行没有batch命令"),
+  };
+
+  match result {
+    Ok(Some(value)) => println!("{}", value),
+    Ok(None) => (),
+    Err(e) => fail(e),
+  }
+}
+
+// 一页一页地取，取到一页就打印一页，每行是key和value，用tab分开。limit是总共最多打印多少个
+fn scan(client: &mut KvClient, start: Option<String>, end: Option<String>, mut limit: Option<usize>) -> Result<()> {
+  let mut cursor = None;
+  loop {
+    let page = client.scan(start.clone(), end.clone(), limit, cursor)?;
+    for (key, value) in &page.items {
+      println!("{}\t{}", key, value);
+    }
+    if let Some(limit) = limit.as_mut() {
+      *limit = limit.saturating_sub(page.items.len());
+      if *limit == 0 {
+        return Ok(());
+      }
+    }
+    // 一页取满了也有cursor，后面可能已经没有数据了，取到空的一页就结束
+    cursor = page.cursor;
+    if cursor.is_none() || page.items.is_empty() {
+      return Ok(());
+    }
+  }
+}
+
+// 打印错误，按错误的大类退出，脚本根据退出码判断失败的原因
+fn fail(e: KvError) -> ! {
+  eprintln!("{}", e);
+  exit(e.category().exit_code());
+}
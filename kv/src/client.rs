@@ -1,79 +1,225 @@
-use std::{io::{BufReader, BufWriter, Result, Write}, net::TcpStream};
+use std::{io::{BufReader, BufWriter, Error, ErrorKind, Write}, net::{TcpStream, ToSocketAddrs}, time::Duration};
 
-use clap::Parser;
-use kv::{kv::command::{Cli, Command}, req::{Request, Response}};
 use serde::Deserialize;
 use serde_json::{de::IoRead, Deserializer};
 
-const DEFAULT_SERVER_PORT: &str = "127.0.0.1:4000";
+use crate::{error::{KvError, Result}, kv::{batch::WriteBatch, command::Command}, req::{Body, Request, Response, ScanPage}};
 
-struct Connection {
+/// 客户端，连接会一直保持，可以连续发送多个请求
+///
+/// ```no_run
+/// use kv::client::KvClient;
+///
+/// let mut client = KvClient::connect("127.0.0.1:4000")?;
+/// client.set("foo".to_string(), "bar".to_string())?;
+/// assert_eq!(Some("bar".to_string()), client.get("foo".to_string())?);
+/// # Ok::<(), kv::error::KvError>(())
+/// ```
+pub struct KvClient {
   stream_writer: BufWriter<TcpStream>,
   stream_reader: Deserializer<IoRead<BufReader<TcpStream>>>,
 }
 
-impl Connection {
-  fn open(port: String) -> Result<Connection> {
-    let connect = TcpStream::connect(port)?;
+impl KvClient {
+  pub fn connect(addr: impl ToSocketAddrs) -> Result<KvClient> {
+    let connect = TcpStream::connect(addr)?;
     let stream_writer = BufWriter::new(connect.try_clone()?);
     let stream_reader = Deserializer::from_reader(BufReader::new(connect));
 
-    Ok(Connection {
+    Ok(KvClient {
       stream_writer,
       stream_reader 
     })
   }
 
-  fn get(&mut self, key: String) -> Result<Response> {
-    let command = Command::Get { key };
-    serde_json::to_writer(&mut self.stream_writer, &Request{ command })?;
-    self.stream_writer.flush()?;
+  /// 根据key取值，key不存在时返回`None`
+  pub fn get(&mut self, key: String) -> Result<Option<String>> {
+    self.send(Command::Get { key })
+  }
 
-    let resp = Response::deserialize(&mut self.stream_reader)?;
-    Ok(resp)
+  pub fn set(&mut self, key: String, value: String) -> Result<()> {
+    self.send(Command::set(key, value)).map(|_| ())
   }
 
-  fn set(&mut self, key: String, value: String) -> Result<Response> {
-    let command = Command::Set { key, value };
-    serde_json::to_writer(&mut self.stream_writer, &Request{ command })?;
-    self.stream_writer.flush()?;
+  /// set，ttl之后过期
+  pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+    self.send(Command::Set { key, value, ttl: Some(ttl.as_millis() as u64), expires_at: None, version: None }).map(|_| ())
+  }
+
+  /// 根据key取值和它的版本号，key不存在时返回`None`
+  pub fn get_versioned(&mut self, key: String) -> Result<Option<(String, u64)>> {
+    let resp = self.request(Command::GetVersioned { key })?;
+    match (resp.result.map_err(KvError::from)?, resp.body) {
+      (Some(value), Some(Body::Version(version))) => Ok(Some((value, version))),
+      (None, _) => Ok(None),
+      _ => Err(KvError::Protocol("get-versioned的响应格式不对".to_string())),
+    }
+  }
+
+  /// key不存在时才set，返回新的版本号，key已经存在时返回[`KvError::ConditionFailed`]错误
+  pub fn set_if_absent(&mut self, key: String, value: String) -> Result<u64> {
+    self.send_conditional(Command::SetIfAbsent { key, value })
+  }
+
+  /// key当前的值等于expected时才set，返回新的版本号，条件不满足时返回[`KvError::ConditionFailed`]错误
+  pub fn set_if_equals(&mut self, key: String, expected: String, value: String) -> Result<u64> {
+    self.send_conditional(Command::SetIfEquals { key, value, expected })
+  }
+
+  /// key当前的版本号等于version时才set，返回新的版本号，条件不满足时返回[`KvError::ConditionFailed`]错误
+  pub fn set_if_version(&mut self, key: String, version: u64, value: String) -> Result<u64> {
+    self.send_conditional(Command::SetIfVersion { key, value, version })
+  }
+
+  pub fn remove(&mut self, key: String) -> Result<()> {
+    self.send(Command::Remove { key }).map(|_| ())
+  }
+
+  /// 原子写入一组set和remove，要么全部生效，要么都不生效
+  pub fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+    self.send(Command::Batch { ops: batch.into_commands() }).map(|_| ())
+  }
+
+  /// 让服务端立即压缩合并数据文件，压缩完成后返回
+  pub fn compact(&mut self) -> Result<()> {
+    self.send(Command::Compact).map(|_| ())
+  }
+
+  /// 取一页[start, end)范围内的键值对，`limit`是一页的数量，不填用服务端默认的数量
+  ///
+  /// 第一页`cursor`传`None`，之后传上一页返回的cursor，返回的cursor是`None`或者这一页是空的时说明取完了。
+  pub fn scan(&mut self, start: Option<String>, end: Option<String>, limit: Option<usize>, cursor: Option<String>) -> Result<ScanPage> {
+    match self.request(Command::Scan { start, end, limit, cursor })?.into_body()? {
+      Body::Scan(page) => Ok(page),
+      _ => Err(KvError::Protocol("scan的响应格式不对".to_string())),
+    }
+  }
 
-    let resp = Response::deserialize(&mut self.stream_reader)?;
-    Ok(resp)
+  /// 按顺序返回以prefix开头的所有key
+  pub fn keys(&mut self, prefix: String) -> Result<Vec<String>> {
+    let mut keys = Vec::new();
+    self.for_each_key(prefix, |key| keys.push(key))?;
+    Ok(keys)
   }
 
-  fn remove(&mut self, key: String) -> Result<Response> {
-    let command = Command::Remove { key };
+  /// 按顺序处理以prefix开头的每个key，服务端分多个响应发送，每收到一个响应就处理其中的key，不用等全部收完
+  pub fn for_each_key(&mut self, prefix: String, mut f: impl FnMut(String)) -> Result<()> {
+    self.write_request(Command::Keys { prefix })?;
+    loop {
+      match self.read_response()?.into_body()? {
+        Body::Keys { keys, more } => {
+          keys.into_iter().for_each(&mut f);
+          if !more {
+            return Ok(());
+          }
+        },
+        _ => return Err(KvError::Protocol("keys的响应格式不对".to_string())),
+      }
+    }
+  }
+
+  /// 以prefix开头的key的数量
+  pub fn count(&mut self, prefix: String) -> Result<usize> {
+    match self.request(Command::Count { prefix })?.into_body()? {
+      Body::Count(count) => Ok(count),
+      _ => Err(KvError::Protocol("count的响应格式不对".to_string())),
+    }
+  }
+
+  // 发送请求，等待响应，服务端返回的错误按错误码转成对应的错误
+  fn send(&mut self, command: Command) -> Result<Option<String>> {
+    self.request(command)?.result.map_err(KvError::from)
+  }
+
+  // 发送条件写的请求，返回新的版本号
+  fn send_conditional(&mut self, command: Command) -> Result<u64> {
+    match self.request(command)?.into_body()? {
+      Body::Version(version) => Ok(version),
+      _ => Err(KvError::Protocol("条件写的响应格式不对".to_string())),
+    }
+  }
+
+  // 发送请求，等待响应
+  fn request(&mut self, command: Command) -> Result<Response> {
+    self.write_request(command)?;
+    self.read_response()
+  }
+
+  fn write_request(&mut self, command: Command) -> Result<()> {
     serde_json::to_writer(&mut self.stream_writer, &Request{ command })?;
     self.stream_writer.flush()?;
+    Ok(())
+  }
 
-    let resp = Response::deserialize(&mut self.stream_reader)?;
-    Ok(resp)
+  fn read_response(&mut self) -> Result<Response> {
+    Response::deserialize(&mut self.stream_reader).map_err(|e| {
+      if e.is_eof() {
+        KvError::Io(Error::from(ErrorKind::UnexpectedEof))
+      } else if e.is_io() {
+        KvError::Io(e.into())
+      } else {
+        KvError::Protocol(e.to_string())
+      }
+    })
   }
 }
 
-fn main() {
-  let parse = Cli::parse();
-
-  let port = parse
-    .port
-    .or(Some(String::from(DEFAULT_SERVER_PORT)));
-
-  let mut connect = Connection::open(port.unwrap())
-    .expect("连接服务器异常！");
-
-  match parse.command {
-    Command::Set { key, value } => {
-      let set = connect.set(key, value).unwrap();
-      println!("{:?}", set);
-    },
-    Command::Get { key } => {
-      let get = connect.get(key).unwrap();
-      println!("{:?}", get);
-    },
-    Command::Remove { key } => {
-      let remove = connect.remove(key).unwrap();
-      println!("{:?}", remove); 
-    },
-  }
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+  use std::{net::TcpListener, thread, time::Duration};
+
+  use crate::{error::{KvError, Result}, kv::{batch::WriteBatch, memory::MemoryStore}, server::{KvServer, ServerConfig}};
+
+  use super::KvClient;
+
+  #[test]
+  fn test_client() -> Result<()> {
+    let server = KvServer::new(MemoryStore::new(), ServerConfig::default())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    thread::spawn(move || server.serve(listener));
+
+    // 同一个连接上连续发送多个请求
+    let mut client = KvClient::connect(addr)?;
+    assert_eq!(None, client.get("key".to_string())?);
+    client.set("key".to_string(), "value".to_string())?;
+    assert_eq!(Some("value".to_string()), client.get("key".to_string())?);
+    client.remove("key".to_string())?;
+    assert_eq!(None, client.get("key".to_string())?);
+    assert!(matches!(client.remove("key".to_string()), Err(KvError::KeyNotFound)));
+    client.set_with_ttl("key".to_string(), "value".to_string(), Duration::ZERO)?;
+    assert_eq!(None, client.get("key".to_string())?);
+
+    client.set("a".to_string(), "1".to_string())?;
+    client.set("b".to_string(), "2".to_string())?;
+    let page = client.scan(None, None, Some(1), None)?;
+    assert_eq!(vec![("a".to_string(), "1".to_string())], page.items);
+    let page = client.scan(None, None, Some(1), page.cursor)?;
+    assert_eq!(vec![("b".to_string(), "2".to_string())], page.items);
+    let page = client.scan(None, None, Some(1), page.cursor)?;
+    assert!(page.items.is_empty());
+    assert_eq!(None, page.cursor);
+
+    assert_eq!(vec!["a".to_string(), "b".to_string()], client.keys(String::new())?);
+    assert_eq!(1, client.count("b".to_string())?);
+    // keys的响应读完之后，连接上还能接着发请求
+    assert!(client.keys("c".to_string())?.is_empty());
+    assert_eq!(Some("1".to_string()), client.get("a".to_string())?);
+
+    // 条件写
+    let version = client.set_if_absent("cas".to_string(), "1".to_string())?;
+    assert_eq!(Some(("1".to_string(), version)), client.get_versioned("cas".to_string())?);
+    assert!(matches!(client.set_if_version("cas".to_string(), version + 1, "2".to_string()), Err(KvError::ConditionFailed)));
+    let version = client.set_if_version("cas".to_string(), version, "2".to_string())?;
+    assert!(matches!(client.set_if_equals("cas".to_string(), "1".to_string(), "3".to_string()), Err(KvError::ConditionFailed)));
+    assert!(client.set_if_equals("cas".to_string(), "2".to_string(), "3".to_string())? > version);
+    assert_eq!(None, client.get_versioned("none".to_string())?);
+
+    // 批量写入
+    client.write_batch(WriteBatch::new().remove("a".to_string()).set_with_ttl("c".to_string(), "3".to_string(), Duration::from_secs(60)))?;
+    assert_eq!(None, client.get("a".to_string())?);
+    assert_eq!(Some("3".to_string()), client.get("c".to_string())?);
+    assert!(matches!(client.write_batch(WriteBatch::new().remove("a".to_string())), Err(KvError::KeyNotFound)));
+    Ok(())
+  }
+}
@@ -0,0 +1,82 @@
+use std::{collections::BTreeMap, ops::{Bound, RangeBounds}, time::Duration};
+
+use crate::{error::Result, kv::batch::WriteBatch};
+
+/// 存储引擎，KvServer通过它来存取数据
+///
+/// 引擎会被clone到处理连接的各个线程中，clone出来的对象操作的是同一份数据。
+pub trait KvsEngine: Clone + Send + 'static {
+  /// 存储一个键值对，key已存在时覆盖
+  fn set(&self, key: String, value: String) -> Result<()>;
+
+  /// 存储一个键值对，ttl之后过期，过期的key和不存在的key一样
+  fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()>;
+
+  /// 根据key取值，key不存在或者已经过期时返回`None`
+  fn get(&self, key: String) -> Result<Option<String>>;
+
+  /// 根据key取值和它的版本号，key不存在或者已经过期时返回`None`
+  ///
+  /// 每次set都会分配一个新的版本号，同一个引擎中的版本号单调递增。
+  fn get_versioned(&self, key: String) -> Result<Option<(String, u64)>>;
+
+  /// key不存在或者已经过期时才set，返回新的版本号，
+  /// 条件不满足时返回[`KvError::ConditionFailed`](crate::error::KvError::ConditionFailed)错误
+  fn set_if_absent(&self, key: String, value: String) -> Result<u64>;
+
+  /// key当前的值等于expected时才set，返回新的版本号，条件不满足时返回`ConditionFailed`错误
+  fn set_if_equals(&self, key: String, expected: String, value: String) -> Result<u64>;
+
+  /// key当前的版本号等于version时才set，返回新的版本号，条件不满足时返回`ConditionFailed`错误
+  fn set_if_version(&self, key: String, version: u64, value: String) -> Result<u64>;
+
+  /// 删除key，key不存在时返回[`KvError::KeyNotFound`](crate::error::KvError::KeyNotFound)错误
+  fn remove(&self, key: String) -> Result<()>;
+
+  /// 原子写入一组set和remove，要么全部生效，要么都不生效
+  ///
+  /// 其中有remove的key不存在时整批都不写入，返回`KeyNotFound`错误。
+  fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+
+  /// 按key的顺序返回range范围内最多limit个键值对，范围是空的时返回空的列表
+  fn scan<R: RangeBounds<String>>(&self, range: R, limit: usize) -> Result<Vec<(String, String)>>;
+
+  /// 按顺序返回以prefix开头的所有key，prefix是空字符串时返回所有的key
+  fn keys(&self, prefix: &str) -> Result<Vec<String>>;
+
+  /// 按顺序返回以prefix开头、排在after后面的最多limit个key，after是`None`时从第一个开始
+  ///
+  /// 用来一批一批地取key，每次调用单独查一次，上一批的最后一个key作为下一次的after。
+  fn keys_after(&self, prefix: &str, after: Option<&str>, limit: usize) -> Result<Vec<String>>;
+
+  /// 以prefix开头的key的数量
+  fn count(&self, prefix: &str) -> Result<usize>;
+
+  /// 立即压缩合并数据，没有需要压缩的数据时什么也不做
+  fn compact(&self) -> Result<()>;
+}
+
+// 范围里一个key也没有时返回true，开始比结束大的范围交给BTreeMap::range会panic，要先判断
+pub(crate) fn is_empty_range<R: RangeBounds<String>>(range: &R) -> bool {
+  match (range.start_bound(), range.end_bound()) {
+    (Bound::Included(start), Bound::Included(end)) => start > end,
+    (Bound::Included(start) | Bound::Excluded(start), Bound::Included(end) | Bound::Excluded(end)) => start >= end,
+    _ => false,
+  }
+}
+
+// 按顺序遍历map中key以prefix开头的数据，从prefix开始往后找，遇到第一个不匹配的就停下
+pub(crate) fn prefix_entries<'a, V>(map: &'a BTreeMap<String, V>, prefix: &'a str) -> impl Iterator<Item = (&'a String, &'a V)> {
+  prefix_entries_after(map, prefix, None)
+}
+
+// 和prefix_entries一样，有after时从after后面开始找
+pub(crate) fn prefix_entries_after<'a, V>(map: &'a BTreeMap<String, V>, prefix: &'a str, after: Option<&'a str>) -> impl Iterator<Item = (&'a String, &'a V)> {
+  let start = match after {
+    Some(after) if after >= prefix => Bound::Excluded(after),
+    _ => Bound::Included(prefix),
+  };
+  map
+    .range::<str, _>((start, Bound::Unbounded))
+    .take_while(move |(key, _)| key.starts_with(prefix))
+}
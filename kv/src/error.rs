@@ -0,0 +1,202 @@
+use std::{fmt, io};
+
+use serde::{Deserialize, Serialize};
+
+/// KvStore、服务端和客户端共用的错误
+#[derive(Debug)]
+pub enum KvError {
+  /// remove的key不存在
+  KeyNotFound,
+  /// 读写文件或者网络出错
+  Io(io::Error),
+  /// json序列化、反序列化失败
+  Serialization(serde_json::Error),
+  /// 数据文件、索引文件中的数据损坏
+  Corruption(String),
+  /// 请求或者响应的格式不对
+  Protocol(String),
+  /// KvStore是只读打开的，不能修改数据
+  ReadOnly,
+  /// 条件写的条件不满足，比如key已经存在、值或者版本号对不上，数据没有修改
+  ConditionFailed,
+  /// 服务端返回的其它错误
+  Remote { code: ErrorCode, message: String },
+}
+
+/// 错误码，在服务端和客户端之间传递，客户端根据它判断错误的类型
+///
+/// 序列化成小写下划线的字符串，已有的值不会再改。
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+  KeyNotFound,
+  Io,
+  Serialization,
+  Corruption,
+  Protocol,
+  ReadOnly,
+  ConditionFailed,
+}
+
+/// 错误的大类，用来区分key不存在、请求有问题和服务端失败
+///
+/// 客户端命令行按它决定退出码。
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCategory {
+  /// key不存在
+  NotFound,
+  /// 请求本身有问题，比如格式不对、服务端是只读的，重试也没用
+  InvalidRequest,
+  /// 服务端处理失败，比如读写数据文件出错、数据损坏
+  Server,
+  /// 本地的io错误，对客户端来说就是连不上服务端或者连接断开了
+  Io,
+  /// 条件写的条件不满足，通常是被别人先改了，重新读一次再试
+  ConditionFailed,
+}
+
+impl ErrorCategory {
+  /// 客户端命令行的退出码，0是成功，2留给命令行参数错误
+  pub fn exit_code(&self) -> i32 {
+    match self {
+      ErrorCategory::Io => 1,
+      ErrorCategory::NotFound => 3,
+      ErrorCategory::InvalidRequest => 4,
+      ErrorCategory::Server => 5,
+      ErrorCategory::ConditionFailed => 6,
+    }
+  }
+}
+
+pub type Result<T> = std::result::Result<T, KvError>;
+
+impl KvError {
+  /// 错误对应的错误码
+  pub fn code(&self) -> ErrorCode {
+    match self {
+      KvError::KeyNotFound => ErrorCode::KeyNotFound,
+      KvError::Io(_) => ErrorCode::Io,
+      KvError::Serialization(_) => ErrorCode::Serialization,
+      KvError::Corruption(_) => ErrorCode::Corruption,
+      KvError::Protocol(_) => ErrorCode::Protocol,
+      KvError::ReadOnly => ErrorCode::ReadOnly,
+      KvError::ConditionFailed => ErrorCode::ConditionFailed,
+      KvError::Remote { code, .. } => *code,
+    }
+  }
+
+  /// 错误的大类
+  pub fn category(&self) -> ErrorCategory {
+    match self {
+      KvError::Io(_) => ErrorCategory::Io,
+      e => e.code().category(),
+    }
+  }
+
+  /// 服务端返回的错误码和错误信息转成错误，能对应上的转成具体的错误
+  pub fn remote(code: ErrorCode, message: String) -> KvError {
+    match code {
+      ErrorCode::KeyNotFound => KvError::KeyNotFound,
+      ErrorCode::Corruption => KvError::Corruption(message),
+      ErrorCode::Protocol => KvError::Protocol(message),
+      ErrorCode::ReadOnly => KvError::ReadOnly,
+      ErrorCode::ConditionFailed => KvError::ConditionFailed,
+      ErrorCode::Io | ErrorCode::Serialization => KvError::Remote { code, message },
+    }
+  }
+}
+
+impl ErrorCode {
+  /// 错误码所属的大类，服务端的io错误算服务端失败
+  pub fn category(&self) -> ErrorCategory {
+    match self {
+      ErrorCode::KeyNotFound => ErrorCategory::NotFound,
+      ErrorCode::Protocol | ErrorCode::ReadOnly => ErrorCategory::InvalidRequest,
+      ErrorCode::Io | ErrorCode::Serialization | ErrorCode::Corruption => ErrorCategory::Server,
+      ErrorCode::ConditionFailed => ErrorCategory::ConditionFailed,
+    }
+  }
+}
+
+impl fmt::Display for ErrorCode {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let code = match self {
+      ErrorCode::KeyNotFound => "key_not_found",
+      ErrorCode::Io => "io",
+      ErrorCode::Serialization => "serialization",
+      ErrorCode::Corruption => "corruption",
+      ErrorCode::Protocol => "protocol",
+      ErrorCode::ReadOnly => "read_only",
+      ErrorCode::ConditionFailed => "condition_failed",
+    };
+    write!(f, "{}", code)
+  }
+}
+
+impl fmt::Display for KvError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      KvError::KeyNotFound => write!(f, "Key not found"),
+      KvError::Io(e) => write!(f, "{}", e),
+      KvError::Serialization(e) => write!(f, "序列化失败: {}", e),
+      KvError::Corruption(msg) => write!(f, "数据损坏: {}", msg),
+      KvError::Protocol(msg) => write!(f, "协议错误: {}", msg),
+      KvError::ReadOnly => write!(f, "KvStore是只读打开的，不能修改数据"),
+      KvError::ConditionFailed => write!(f, "条件不满足，数据没有修改"),
+      KvError::Remote { message, .. } => write!(f, "{}", message),
+    }
+  }
+}
+
+impl std::error::Error for KvError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      KvError::Io(e) => Some(e),
+      KvError::Serialization(e) => Some(e),
+      _ => None,
+    }
+  }
+}
+
+/// 读出来的数据有问题的`ErrorKind::InvalidData`转成[`KvError::Corruption`]，其它的是[`KvError::Io`]
+impl From<io::Error> for KvError {
+  fn from(e: io::Error) -> Self {
+    match e.kind() {
+      io::ErrorKind::InvalidData => KvError::Corruption(e.to_string()),
+      _ => KvError::Io(e),
+    }
+  }
+}
+
+impl From<serde_json::Error> for KvError {
+  fn from(e: serde_json::Error) -> Self {
+    KvError::Serialization(e)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::{Error, ErrorKind};
+
+  use crate::req::ErrorResponse;
+
+  use super::{ErrorCategory, ErrorCode, KvError};
+
+  #[test]
+  fn test_error_response() {
+    let response = ErrorResponse::from(KvError::KeyNotFound);
+    assert_eq!(r#"{"code":"key_not_found","message":"Key not found"}"#, serde_json::to_string(&response).unwrap());
+
+    // 服务端返回的错误转回来，key不存在和服务端失败能区分开
+    let remote = |code| KvError::from(ErrorResponse { code, message: "error".to_string() });
+    assert!(matches!(remote(ErrorCode::KeyNotFound), KvError::KeyNotFound));
+    assert_eq!(ErrorCategory::NotFound, remote(ErrorCode::KeyNotFound).category());
+    assert_eq!(ErrorCategory::InvalidRequest, remote(ErrorCode::ReadOnly).category());
+    assert_eq!(ErrorCategory::Server, remote(ErrorCode::Io).category());
+    assert_eq!(ErrorCategory::Server, remote(ErrorCode::Corruption).category());
+    assert!(matches!(remote(ErrorCode::ConditionFailed), KvError::ConditionFailed));
+    assert_eq!(6, remote(ErrorCode::ConditionFailed).category().exit_code());
+    // 本地的io错误
+    assert_eq!(ErrorCategory::Io, KvError::Io(Error::from(ErrorKind::ConnectionReset)).category());
+  }
+}
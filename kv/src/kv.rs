@@ -1,208 +1,750 @@
 // kv.rs
 use std::{
-  collections::{BTreeMap, HashMap}, env::current_dir, ffi::OsStr, fs::{self, create_dir_all, read_dir, File, OpenOptions}, io::{self, BufReader, Error, ErrorKind, Read, Result, Seek, SeekFrom, Write}, ops::Range, path::PathBuf
+  collections::{BTreeMap, HashMap}, env::current_dir, ffi::OsStr, fs::{create_dir_all, read_dir, File, OpenOptions}, io::{self, BufRead, BufReader, Error, ErrorKind, Read, Seek, SeekFrom, Write}, mem, ops::{Range, RangeBounds}, path::{Path, PathBuf}, sync::{Arc, Mutex, RwLock, Weak}, thread, time::{Duration, Instant}
 };
 
 use serde_json::Deserializer;
 
+use crate::{engine::{is_empty_range, prefix_entries, prefix_entries_after, KvsEngine}, error::{KvError, Result}};
+
 use self::{
+  builder::{CompactionPolicy, Durability, KvStoreBuilder, RecoveryMode},
+  checkpoint::{Checkpoint, Checkpointer, IndexSnapshot},
+  compaction::{remove_leftovers, Compaction, Compactor},
+  batch::{BatchOp, WriteBatch},
   command::{CmdIdx, Command}, 
+  hint::read_hint,
+  lock::DirLock,
+  reader::Readers,
+  record::{corruption, file_header, now_millis, FileFormat, Record, RecordType, FILE_HEADER_LEN, FILE_VERSION},
   writer::WriterWithPos
 };
 
+pub mod batch;
+pub mod builder;
+mod checkpoint;
 pub mod command;
+mod compaction;
+mod hint;
+mod lock;
+pub mod memory;
+pub mod reader;
+pub mod record;
 pub mod writer;
 
-// 指令数据压缩阈值
-const COMPACTION_THRESHOLD: u64 = 1024;
-
 /// KvStore, 存储键值对的上下文结构体
+///
+/// KvStore可以clone，clone出来的对象共享同一份数据，可以交给多个线程同时使用。
+/// 读操作之间、读和写之间互不阻塞，只有写操作之间是串行的。
+///
+/// 打开时会锁住数据目录，同一个目录同时只能被一个KvStore打开，最后一个clone出来的对象drop之后释放。
+/// 只读打开的不加锁，可以和一个正在写的KvStore同时打开同一个目录。
+#[derive(Clone)]
 pub struct KvStore {
-  // 数据文件的位置
-  data_path: PathBuf,
+  // 数据索引，多个线程可以同时读，写锁只在更新索引的一瞬间持有
+  index: Arc<RwLock<BTreeMap<String, CmdIdx>>>,
+  // 数据文件路径下所有文件reader
+  readers: Readers,
+  // 写数据的部分，同一时间只有一个线程在写
+  writer: Arc<Mutex<KvWriter>>,
+  // 等待写入的指令
+  queue: Arc<Mutex<WriteQueue>>,
+  // 是否组提交
+  group_commit: bool,
+  // 是否只读打开
+  read_only: bool,
+  // 压缩用到的数据，和后台压缩线程共享，在当前线程手动压缩时使用
+  compaction: Compaction,
+  // 后台压缩线程，只读打开时不会压缩，没有这个线程
+  compactor: Option<Arc<Compactor>>,
+  // 写索引快照用到的数据
+  checkpoint: Checkpoint,
+  // 定时写索引快照的后台线程，最后一个KvStore drop的时候线程退出
+  _checkpointer: Option<Arc<Checkpointer>>,
+  // 数据目录的锁，放在最后，等后台线程都退出了再释放，只读打开时没有
+  _lock: Option<Arc<DirLock>>,
+}
+
+// 组提交时排队等待写入的指令
+#[derive(Default)]
+struct WriteQueue {
+  // 下一个排队的号
+  next_ticket: u64,
+  // 排队中的指令和它的号
+  pending: Vec<(u64, Command)>,
+}
+
+// 写数据时需要的状态
+struct KvWriter {
   // 当前正在操作的数据文件
   // 数据文件的命名方式使用数字递增的方式 1.log, 2.log, 3.log。。。
   cur_data_file_name: u32,
-  // 当前数据文件的writer
-  writer: WriterWithPos<File>,
-  // 数据文件路径下所有文件reader
-  // 使用hashmap来存，key: 文件名, value: writer
-  readers: HashMap<u32, BufReader<File>>,
-  // 数据索引
-  index: BTreeMap<String, CmdIdx>,
+  // 当前数据文件的writer，只读打开时没有
+  writer: Option<WriterWithPos<File>>,
   // 未被压缩的指令数据长度
   uncompacted: u64,
+  // 索引指向的有效数据长度
+  live: u64,
+  // 什么时候自动压缩
+  compaction_policy: CompactionPolicy,
+  // 两次自动压缩之间最短的间隔
+  compaction_min_interval: Duration,
+  // 上次压缩完成的时间
+  last_compaction: Option<Instant>,
+  // 写数据后什么时候fsync
+  durability: Durability,
+  // 有没有还没fsync的数据
+  dirty: bool,
+  // 组提交时，被其它线程一起写入的指令的结果，key: 排队的号
+  done: HashMap<u64, Result<()>>,
+  // 是否已经通知了后台线程压缩，压缩完成之前不再通知
+  compacting: bool,
+  // 最后分配出去的数据版本号，每条set都分配一个新的，整个KvStore单调递增
+  version: u64,
+}
+
+impl KvWriter {
+  // 当前数据文件的writer，只读打开时返回错误
+  fn data_file(&mut self) -> Result<&mut WriterWithPos<File>> {
+    self.writer.as_mut().ok_or(KvError::ReadOnly)
+  }
+
+  // 写完一条数据后，按fsync策略把数据落盘
+  fn commit(&mut self) -> Result<()> {
+    match self.durability {
+      Durability::Always => self.data_file()?.sync()?,
+      Durability::Interval(_) | Durability::Never => {
+        self.dirty = true;
+        self.data_file()?.flush()?
+      },
+    }
+    Ok(())
+  }
+
+  // 写入失败后回滚到start，写了一半的数据留在文件中的话，后面的数据接在它后面，打开时会被当成损坏的数据
+  fn rollback(&mut self, start: u64) {
+    if let Err(e) = self.data_file().and_then(|data_file| Ok(data_file.rollback(start)?)) {
+      println!("数据文件回滚失败！{}", e);
+    }
+  }
+
+  // 是否需要自动压缩
+  fn should_compact(&self) -> bool {
+    !self.compacting
+      && self.compaction_policy.should_compact(self.uncompacted, self.live)
+      && self.last_compaction.is_none_or(|last| last.elapsed() >= self.compaction_min_interval)
+  }
+
+  // 后台线程定时调用
+  fn sync(&mut self) -> Result<()> {
+    if self.dirty {
+      self.data_file()?.sync()?;
+      self.dirty = false;
+    }
+    Ok(())
+  }
 }
 
 impl KvStore {
-  // 初始化KvStore
+  // 初始化KvStore，数据目录为current_dir/data
   pub fn open() -> Result<KvStore> {
-    let data_path = data_dir()?;
+    KvStore::open_at(data_dir()?)
+  }
+
+  /// 使用指定的数据目录初始化KvStore
+  pub fn open_at(path: impl AsRef<Path>) -> Result<KvStore> {
+    KvStore::builder(path).open()
+  }
+
+  /// 带选项的初始化方式，见[`KvStoreBuilder`]
+  pub fn builder(path: impl AsRef<Path>) -> KvStoreBuilder {
+    KvStoreBuilder::new(path)
+  }
+
+  fn open_with(builder: KvStoreBuilder) -> Result<KvStore> {
+    let data_path = builder.path;
+    let read_only = builder.read_only;
+    // 创建目录，先锁住数据目录，再做任何修改。只读打开时什么都不改，也不用加锁
+    let lock = if read_only {
+      None
+    } else {
+      create_dir_all(&data_path)?;
+      let lock = DirLock::acquire(&data_path)?;
+      // 上次压缩到一半留下的临时文件
+      remove_leftovers(&data_path)?;
+      Some(Arc::new(lock))
+    };
     // 从数据目录中读出文件名，并按数字大小排序，以便计算最新的数据文件名
     let sorted_file_names = sorted_file_names(&data_path)?;
-    // 当前正在操作的数据文件名，从所有的文件中取出最大的，+1。
-    let cur_data_file_name = sorted_file_names.last().unwrap_or(&0) + 1;
+    // 当前正在操作的数据文件名，从所有的文件中取出最大的，+1。只读打开时就是最新的数据文件
+    let newest = sorted_file_names.last().cloned().unwrap_or(0);
+    let cur_data_file_name = if read_only { newest } else { newest + 1 };
     // readers
-    let mut readers = HashMap::new();
-    // 内存中的数据索引
-    let mut index = BTreeMap::new();
-    // 未被压缩的指令数据长度
-    let mut uncompacted = 0;
-    uncompacted += load_idx(&data_path, sorted_file_names, &mut readers, &mut index)?;
+    let readers = Readers::default();
+    // 有可用的索引快照时从快照开始，只回放快照之后的数据
+    let snapshot = IndexSnapshot::load(&data_path, &sorted_file_names).unwrap_or_else(|e| {
+      println!("索引快照不可用，回放所有数据文件：{}", e);
+      None
+    });
+    let (covered, mut index, mut uncompacted, snapshot_version) = match snapshot {
+      Some(snapshot) => (Some((snapshot.file, snapshot.pos)), snapshot.index, snapshot.uncompacted, snapshot.max_version),
+      None => (None, BTreeMap::new(), 0, 0),
+    };
+    let (replayed, replayed_version) = load_idx(&data_path, sorted_file_names, &readers, &mut index, builder.recovery, covered, read_only)?;
+    uncompacted += replayed;
+    // 从已经分配过的最大版本号之后继续分配
+    let version = snapshot_version.max(replayed_version);
+    // 已经过期的key不放进索引，它们的数据都算作可以压缩的数据
+    uncompacted += remove_expired(&mut index, now_millis());
+    // 有效数据长度
+    let live = index.values().map(|cmd_idx| cmd_idx.len).sum();
     // writer, 顺带把reader也给创建放入readers中
-    let writer = new_data_file(&data_path, cur_data_file_name, &mut readers)?;
+    let writer = if read_only { None } else { Some(new_data_file(&data_path, cur_data_file_name, &readers)?) };
+    let writer = Arc::new(Mutex::new(KvWriter {
+      cur_data_file_name,
+      writer,
+      uncompacted,
+      live,
+      compaction_policy: builder.compaction_policy,
+      compaction_min_interval: builder.compaction_min_interval,
+      last_compaction: None,
+      durability: builder.durability,
+      dirty: false,
+      done: HashMap::new(),
+      compacting: false,
+      version,
+    }));
+    // 定时fsync的后台线程
+    if let (Durability::Interval(interval), false) = (builder.durability, read_only) {
+      spawn_syncer(Arc::downgrade(&writer), interval)?;
+    }
+    let index = Arc::new(RwLock::new(index));
+    let compaction = Compaction {
+      data_path: Arc::new(data_path),
+      index: Arc::clone(&index),
+      readers: readers.clone(),
+      writer: Arc::clone(&writer),
+      running: Arc::new(Mutex::new(())),
+    };
+    // 后台压缩线程，只读打开时不需要
+    let compactor = if read_only { None } else { Some(Arc::new(Compactor::spawn(compaction.clone())?)) };
+    let checkpoint = Checkpoint {
+      data_path: Arc::clone(&compaction.data_path),
+      index: Arc::clone(&index),
+      readers: readers.clone(),
+      writer: Arc::clone(&writer),
+      running: Arc::new(Mutex::new(())),
+    };
+    // 定时写索引快照的后台线程
+    let checkpointer = match builder.checkpoint_interval {
+      Some(interval) if !read_only => Some(Arc::new(Checkpointer::spawn(checkpoint.clone(), interval)?)),
+      _ => None,
+    };
     // 返回
     Ok(KvStore {
-        data_path,
-        cur_data_file_name,
-        writer,
-        readers,
         index,
-        uncompacted,
+        readers,
+        writer,
+        queue: Arc::new(Mutex::new(WriteQueue::default())),
+        group_commit: builder.group_commit,
+        read_only,
+        compaction,
+        compactor,
+        checkpoint,
+        _checkpointer: checkpointer,
+        _lock: lock,
     })
   }
 
   /// set
-  pub fn set(&mut self, key: String, value: String) -> Result<()> {
-    // set命令对象
-    let cmd = Command::Set { key, value };
-    // 数据开始位置
-    let start = self.writer.pos;
-    // 写入json到文件
-    serde_json::to_writer(self.writer.by_ref(), &cmd)?;
-    self.writer.flush()?;
-    // 数据结束位置
-    let end = self.writer.pos;
-    // 将数据插入到内存索引中
-    if let Command::Set { key, .. } = cmd {
-      let insert = self.index.insert(key, (self.cur_data_file_name, (start..end)).into());
-      // 累加可以合并指令数据长度
-      if let Some(cmd_old) = insert {
-          self.uncompacted += cmd_old.len;
-      }
-      // 判断可合并的长度，大于阈值就执行合并方法
-      if COMPACTION_THRESHOLD < self.uncompacted {
-        self.compact()?;
+  pub fn set(&self, key: String, value: String) -> Result<()> {
+    self.write(Command::set(key, value))
+  }
+
+  /// set，ttl之后过期
+  ///
+  /// 过期的时间点和数据一起写入数据文件，重启后依然有效。过期的key读不到，也不能remove，
+  /// 数据在下次压缩或者重新打开时清理掉。
+  pub fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+    let expires_at = now_millis().saturating_add(ttl.as_millis() as u64);
+    self.write(Command::Set { key, value, ttl: None, expires_at: Some(expires_at), version: None })
+  }
+
+  pub fn get(&self, key: String) -> Result<Option<String>> {
+    // 根据key在索引中找到索引数据，拿到后马上释放读锁
+    let cmd_idx = match self.index.read().unwrap().get(&key) {
+      Some(cmd_idx) if !cmd_idx.is_expired(now_millis()) => *cmd_idx,
+      // 没有找到key对应的索引，或者已经过期了
+      _ => return Ok(None),
+    };
+    Ok(self.read_value(&key, cmd_idx)?.map(|(value, _)| value))
+  }
+
+  /// 根据key取值和它的版本号，key不存在或者已经过期时返回`None`
+  pub fn get_versioned(&self, key: String) -> Result<Option<(String, u64)>> {
+    match self.current(&key) {
+      Some(cmd_idx) => self.read_value(&key, cmd_idx),
+      None => Ok(None),
+    }
+  }
+
+  /// key不存在或者已经过期时才set，返回新的版本号，key已经存在时返回[`KvError::ConditionFailed`]错误
+  pub fn set_if_absent(&self, key: String, value: String) -> Result<u64> {
+    self.set_if(key, value, |_, current| Ok(current.is_none()))
+  }
+
+  /// key当前的值等于expected时才set，返回新的版本号，条件不满足时返回[`KvError::ConditionFailed`]错误
+  pub fn set_if_equals(&self, key: String, expected: String, value: String) -> Result<u64> {
+    self.set_if(key, value, |key, current| match current {
+      Some(cmd_idx) => Ok(self.read_value(key, cmd_idx)?.is_some_and(|(current, _)| current == expected)),
+      None => Ok(false),
+    })
+  }
+
+  /// key当前的版本号等于version时才set，返回新的版本号，条件不满足时返回[`KvError::ConditionFailed`]错误
+  ///
+  /// 版本号用[`KvStore::get_versioned`]取，读出来之后被别人改过的话版本号就对不上了。
+  pub fn set_if_version(&self, key: String, version: u64, value: String) -> Result<u64> {
+    self.set_if(key, value, |_, current| Ok(current.is_some_and(|cmd_idx| cmd_idx.version == version)))
+  }
+
+  // 条件写：拿着写锁检查key当前的索引，满足条件才写入，返回新的版本号
+  // 索引只在拿着写锁时修改，检查和写入之间不会有别的写入插进来
+  fn set_if(&self, key: String, value: String, condition: impl FnOnce(&str, Option<CmdIdx>) -> Result<bool>) -> Result<u64> {
+    self.check_writable()?;
+    let mut writer = self.writer.lock().unwrap();
+    if !condition(&key, self.current(&key))? {
+      return Err(KvError::ConditionFailed);
+    }
+    let result = self.write_cmds(&mut writer, vec![(0, Command::set(key, value))]).pop().map(|(_, res)| res).unwrap_or(Ok(()));
+    // 拿着写锁，最后分配的版本号就是刚才写入的
+    let version = writer.version;
+    self.compact_if_needed(&mut writer);
+    result.map(|_| version)
+  }
+
+  // key当前的索引，没有或者已经过期时返回None
+  fn current(&self, key: &str) -> Option<CmdIdx> {
+    self.index.read().unwrap().get(key).filter(|cmd_idx| !cmd_idx.is_expired(now_millis())).copied()
+  }
+
+  /// 按key的顺序返回range范围内最多limit个键值对
+  ///
+  /// 先在索引中取出范围内的索引数据，释放读锁后再去数据文件中读值，扫描期间可以正常写入和压缩。
+  /// 读值的时候key被删掉了就跳过，所以返回的数量可能比limit少。
+  pub fn scan<R: RangeBounds<String>>(&self, range: R, limit: usize) -> Result<Vec<(String, String)>> {
+    if is_empty_range(&range) {
+      return Ok(Vec::new());
+    }
+    let now = now_millis();
+    let cmd_idxs: Vec<(String, CmdIdx)> = self.index
+      .read()
+      .unwrap()
+      .range(range)
+      .filter(|(_, cmd_idx)| !cmd_idx.is_expired(now))
+      .take(limit)
+      .map(|(key, cmd_idx)| (key.clone(), *cmd_idx))
+      .collect();
+    let mut pairs = Vec::with_capacity(cmd_idxs.len());
+    for (key, cmd_idx) in cmd_idxs {
+      if let Some((value, _)) = self.read_value(&key, cmd_idx)? {
+        pairs.push((key, value));
       }
     }
-    
-    Ok(())
+    Ok(pairs)
+  }
+
+  /// 按顺序返回以prefix开头的所有key，只查内存中的索引，不读数据文件
+  pub fn keys(&self, prefix: &str) -> Vec<String> {
+    let now = now_millis();
+    prefix_entries(&self.index.read().unwrap(), prefix)
+      .filter(|(_, cmd_idx)| !cmd_idx.is_expired(now))
+      .map(|(key, _)| key.clone())
+      .collect()
+  }
+
+  /// 按顺序返回以prefix开头、排在after后面的最多limit个key，只查内存中的索引
+  pub fn keys_after(&self, prefix: &str, after: Option<&str>, limit: usize) -> Vec<String> {
+    let now = now_millis();
+    prefix_entries_after(&self.index.read().unwrap(), prefix, after)
+      .filter(|(_, cmd_idx)| !cmd_idx.is_expired(now))
+      .take(limit)
+      .map(|(key, _)| key.clone())
+      .collect()
+  }
+
+  /// 以prefix开头的key的数量，只查内存中的索引
+  pub fn count(&self, prefix: &str) -> usize {
+    let now = now_millis();
+    prefix_entries(&self.index.read().unwrap(), prefix)
+      .filter(|(_, cmd_idx)| !cmd_idx.is_expired(now))
+      .count()
   }
 
-  pub fn get(&mut self, key: String) -> Result<Option<String>> {
-    // 根据key在索引中找到索引数据
-    if let Some(cmd_idx) = self.index.get(&key) {
-      // 根据索引数据中的文件名找到对应数据文件的reader
-      let reader = self.readers.get_mut(&cmd_idx.file).expect("没有找到数据文件！");
-      // 移动reader读取数据文件的指针位置，索引中记录的数据的位置
-      let _ = reader.seek(SeekFrom::Start(cmd_idx.pos))?;
-      // 根据索引记录的数据长度，取出相应的数据
-      let take = reader.take(cmd_idx.len);
-      // 使用serde_json读取数据转换成Command
-      let from_reader = serde_json::from_reader::<_, Command>(take)?;
+  // 根据索引数据读出key的值和版本号
+  fn read_value(&self, key: &str, mut cmd_idx: CmdIdx) -> Result<Option<(String, u64)>> {
+    loop {
+      // 根据索引数据中的文件名找到对应数据文件
+      // 找不到说明这个文件刚被压缩掉了，索引已经指向了新文件，重新查一次索引
+      let Some(file) = self.readers.get(cmd_idx.file) else {
+        cmd_idx = match self.index.read().unwrap().get(key) {
+          Some(cmd_idx) if !cmd_idx.is_expired(now_millis()) => *cmd_idx,
+          _ => return Ok(None),
+        };
+        continue;
+      };
+      // 根据索引记录的位置和长度，取出相应的数据转换成Command
+      let from_reader = file.read_command(cmd_idx.pos, cmd_idx.len)?;
       // 匹配command::set，能匹配到就返回value字段
       if let Command::Set { value, .. } = from_reader {
-          Ok(Some(value))
+          return Ok(Some((value, cmd_idx.version)));
       } else {
         // 匹配不到command::set
-        Ok(None)
+        return Ok(None);
       }
-    } else {
-      // 没有找到key对应的索引
-      Ok(None)
-    }
-  }
-
-  pub fn remove(&mut self, key: String) -> Result<()> {
-    // 判断索引中是否包含这个key
-    if self.index.contains_key(&key) {
-      // 数据的开始位置 
-      let start = self.writer.pos;
-      // 写入文件
-      let cmd_rm = Command::Remove { key };
-      serde_json::to_writer(&mut self.writer, &cmd_rm)?;
-      self.writer.flush()?;
-      // 数据的结束位置
-      let end = self.writer.pos;
-      // 删除索引数据
-      if let Command::Remove { key } = cmd_rm {
-          let remove = self.index.remove(&key);
-          // 累加长度
-          if let Some(cmd_old) = remove {
-              self.uncompacted += cmd_old.len;
-          }
-          // remove指令的长度
-          self.uncompacted += end - start;
+    }
+  }
+
+  /// remove，key不存在时返回[`KvError::KeyNotFound`]错误
+  pub fn remove(&self, key: String) -> Result<()> {
+    self.write(Command::Remove { key })
+  }
+
+  /// 原子写入一组set和remove，要么全部生效，要么都不生效
+  ///
+  /// 整批写成一条批量写入的开始记录和紧跟着的每条指令的记录，只落盘一次。重新打开时，
+  /// 末尾写了一半的批量写入整批丢弃，不会只生效其中的一部分。
+  /// 其中有remove的key不存在时整批都不写入，返回[`KvError::KeyNotFound`]错误。
+  pub fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+    self.check_writable()?;
+    if batch.is_empty() {
+      return Ok(());
+    }
+    let mut writer = self.writer.lock().unwrap();
+    let result = self.write_batch_cmds(&mut writer, batch);
+    self.compact_if_needed(&mut writer);
+    result
+  }
+
+  // 把一批指令作为一个整体写入数据文件，落盘后一起更新索引
+  fn write_batch_cmds(&self, writer: &mut KvWriter, batch: WriteBatch) -> Result<()> {
+    let now = now_millis();
+    let mut cmds = Vec::with_capacity(batch.len());
+    {
+      let index = self.index.read().unwrap();
+      // 这一批中前面的指令对key的影响，true: set过，false: remove过
+      let mut touched = HashMap::new();
+      for op in batch.into_ops() {
+        match op {
+          BatchOp::Set { key, value, ttl } => {
+            touched.insert(key.clone(), true);
+            let expires_at = ttl.map(|ttl| now.saturating_add(ttl.as_millis() as u64));
+            cmds.push(Command::Set { key, value, ttl: None, expires_at, version: None });
+          },
+          BatchOp::Remove { key } => {
+            // 有一个key不存在，整批都不写入
+            if !key_exists(&index, &touched, &key, now) {
+              return Err(KvError::KeyNotFound);
+            }
+            touched.insert(key.clone(), false);
+            cmds.push(Command::Remove { key });
+          },
+        }
       }
-      Ok(())
-    } else {
+    }
+    // 条件都满足了才分配版本号
+    for cmd in cmds.iter_mut() {
+      if let Command::Set { version, .. } = cmd {
+        writer.version += 1;
+        *version = Some(writer.version);
+      }
+    }
+    // 开始记录和每条指令的记录拼在一起，一次写入
+    let file_name = writer.cur_data_file_name;
+    let data_file = writer.data_file()?;
+    let start = data_file.pos;
+    let mut buf = Record::batch(cmds.len()).encode();
+    let batch_header_len = buf.len() as u64;
+    let mut cmd_idxs = Vec::with_capacity(cmds.len());
+    for cmd in &cmds {
+      let pos = start + buf.len() as u64;
+      buf.extend_from_slice(&Record::command(cmd)?.encode());
+      cmd_idxs.push(CmdIdx::from((file_name, pos..start + buf.len() as u64)));
+    }
+    // 写入失败，回滚整批写入的数据，留下写了一半的批量写入，后面的数据打开时会跟着被丢掉
+    if let Err(e) = data_file.write_all(&buf).map_err(KvError::from).and_then(|_| writer.commit()) {
+      writer.rollback(start);
+      return Err(e);
+    }
+    // 开始记录本身不是有效数据，压缩时可以去掉
+    writer.uncompacted += batch_header_len;
+    let mut index = self.index.write().unwrap();
+    for (cmd, cmd_idx) in cmds.into_iter().zip(cmd_idxs) {
+      update_index(writer, &mut index, cmd, cmd_idx);
+    }
+    Ok(())
+  }
+
+  // 写入一条指令
+  // 组提交时先把指令放进队列，拿到写锁的线程会把队列中所有的指令一起写入，只落盘一次
+  fn write(&self, cmd: Command) -> Result<()> {
+    self.check_writable()?;
+    if !self.group_commit {
+      let mut writer = self.writer.lock().unwrap();
+      let result = self.write_cmds(&mut writer, vec![(0, cmd)]).pop().map(|(_, res)| res).unwrap_or(Ok(()));
+      self.compact_if_needed(&mut writer);
+      return result;
+    }
+    // 先排队，拿到一个号
+    let ticket = {
+      let mut queue = self.queue.lock().unwrap();
+      let ticket = queue.next_ticket;
+      queue.next_ticket += 1;
+      queue.pending.push((ticket, cmd));
+      ticket
+    };
+    let mut writer = self.writer.lock().unwrap();
+    // 前面拿到写锁的线程已经把这条指令一起写进去了
+    if let Some(result) = writer.done.remove(&ticket) {
+      return result;
+    }
+    // 把排队的指令都拿出来一起写，其中包括自己的这一条
+    let cmds = mem::take(&mut self.queue.lock().unwrap().pending);
+    let mut own = Ok(());
+    for (cmd_ticket, result) in self.write_cmds(&mut writer, cmds) {
+      if cmd_ticket == ticket {
+        own = result;
+      } else {
+        // 其它线程拿到写锁后来取结果
+        writer.done.insert(cmd_ticket, result);
+      }
+    }
+    self.compact_if_needed(&mut writer);
+    own
+  }
+
+  // 把一组指令写入数据文件，全部写完后只落盘一次，然后再更新索引
+  // 每条指令都是单独的一条记录，有自己的位置，返回每条指令的结果
+  fn write_cmds(&self, writer: &mut KvWriter, cmds: Vec<(u64, Command)>) -> Vec<(u64, Result<()>)> {
+    let mut results = Vec::with_capacity(cmds.len());
+    // 已经写入的指令和它的索引
+    let mut appended = Vec::with_capacity(cmds.len());
+    let mut failure = None;
+    // 这一组开始写入的位置，失败时回滚到这里
+    let start = writer.data_file().map(|data_file| data_file.pos);
+    {
+      let index = self.index.read().unwrap();
+      let now = now_millis();
+      // 这一组中前面的指令对key的影响，true: set过，false: remove过
+      let mut touched = HashMap::new();
+      for (ticket, mut cmd) in cmds {
+        if failure.is_some() {
+          appended.push((ticket, cmd, None));
+          continue;
+        }
+        match &mut cmd {
+          Command::Set { key, version, .. } => {
+            touched.insert(key.clone(), true);
+            // 分配一个新的版本号，和数据一起写入
+            writer.version += 1;
+            *version = Some(writer.version);
+          },
+          Command::Remove { key } => {
+            // 没有找到返回一个错误，这条指令不写入
+            if !key_exists(&index, &touched, key, now) {
+              results.push((ticket, Err(KvError::KeyNotFound)));
+              continue;
+            }
+            touched.insert(key.clone(), false);
+          },
+          _ => (),
+        }
+        let file_name = writer.cur_data_file_name;
+        // 写入一条记录到文件
+        let written = writer.data_file().and_then(|data_file| -> Result<CmdIdx> {
+          // 数据开始位置
+          let start = data_file.pos;
+          data_file.write_all(&Record::command(&cmd)?.encode())?;
+          Ok(CmdIdx::from((file_name, start..data_file.pos)))
+        });
+        match written {
+          Ok(cmd_idx) => appended.push((ticket, cmd, Some(cmd_idx))),
+          Err(e) => {
+            failure = Some(e);
+            appended.push((ticket, cmd, None));
+          },
+        }
+      }
+    }
+    // 按fsync策略落盘
+    if failure.is_none() {
+      failure = writer.commit().err();
+    }
+    // 写入失败，回滚这一组写入的数据，这一组都返回错误
+    if let Some(e) = failure {
+      if let Ok(start) = start {
+        writer.rollback(start);
+      }
+      results.extend(appended.into_iter().map(|(ticket, ..)| (ticket, Err(same_error(&e)))));
+      return results;
+    }
+    // 将数据更新到内存索引中
+    let mut index = self.index.write().unwrap();
+    for (ticket, cmd, cmd_idx) in appended {
+      if let Some(cmd_idx) = cmd_idx {
+        update_index(writer, &mut index, cmd, cmd_idx);
+      }
+      results.push((ticket, Ok(())));
+    }
+    results
+  }
 
-      // 没有找到返回一个错误
-      Err(Error::from(ErrorKind::NotFound))
-    }
-  }
-
-  fn compact(&mut self) -> Result<()> {
-    // 压缩后要写入的文件
-    let compaction_file_name = self.cur_data_file_name + 1;
-    let mut compaction_writer = new_data_file(&self.data_path, compaction_file_name, &mut self.readers)?;
-    // 新来的数据写入的数据文件，区别于合并压缩过的数据文件
-    let cur_data_file_name = compaction_file_name + 1;
-    self.writer = new_data_file(&self.data_path, cur_data_file_name, &mut self.readers)?;
-    // 重新设置当前的数据文件
-    self.cur_data_file_name = cur_data_file_name;
-    // 遍历index
-    for cmd_idx in &mut self.index.values_mut() {
-      // 取出当前索引的reader
-      let reader = self.readers.get_mut(&cmd_idx.file).expect("没有找到数据文件！");
-      // 将索引对应的数据copy到压缩合并后的新数据文件中
-      reader.seek(SeekFrom::Start(cmd_idx.pos))?;
-      let mut take = reader.take(cmd_idx.len);
-      let start = compaction_writer.pos;
-      io::copy(take.by_ref(), compaction_writer.by_ref())?;
-      let end = compaction_writer.pos;
-      // 索引数据重新赋值，新文件的数据位置
-      *cmd_idx = (compaction_file_name, start..end).into();
-    }
-    // 至此，索引中的数据已经全部转移到了新的文件中，这个新文件就所说的指令数据压缩文件
-    compaction_writer.flush()?;
-    // 重置uncompacted
-    self.uncompacted = 0;
-    // 清除旧的数据文件 
-    let old_file_names = self.readers
-      .keys()
-      // 过滤出小于压缩合并文件的文件名，这已经是旧文件了。
-      .filter(|&&res| res < compaction_file_name)
-      .cloned()
-      .collect::<Vec<u32>>();
-    for file_name in old_file_names {
-      // 删除旧文件的reader
-      self.readers.remove(&file_name);
-      // 删除旧文件
-      fs::remove_file(data_dir()?.join(format!("{}.log",file_name)))?;
+  // 按压缩策略判断是否需要压缩，需要就通知后台线程执行合并
+  fn compact_if_needed(&self, writer: &mut KvWriter) {
+    let Some(compactor) = &self.compactor else { return };
+    if writer.should_compact() {
+      writer.compacting = true;
+      compactor.trigger();
     }
+  }
 
+  /// 在当前线程立即执行一次压缩，不管压缩策略，压缩完成后返回
+  ///
+  /// 后台正在压缩的话，等它完成后再压缩一次。
+  pub fn compact_now(&self) -> Result<()> {
+    self.check_writable()?;
+    self.compaction.run()
+  }
+
+  /// 立即把整个索引写一次快照，下次打开时只回放快照之后写入的数据
+  pub fn checkpoint_now(&self) -> Result<()> {
+    self.check_writable()?;
+    self.checkpoint.run()
+  }
+
+  /// 是否只读打开
+  pub fn is_read_only(&self) -> bool {
+    self.read_only
+  }
+
+  // 只读打开的不能修改数据
+  fn check_writable(&self) -> Result<()> {
+    if self.read_only {
+      return Err(KvError::ReadOnly);
+    }
     Ok(())
   }
 
+  /// 可以被压缩掉的数据长度：被覆盖、删除的旧数据和remove指令
+  pub fn uncompacted(&self) -> u64 {
+    self.writer.lock().unwrap().uncompacted
+  }
+
+  /// 索引指向的有效数据长度
+  pub fn live_size(&self) -> u64 {
+    self.writer.lock().unwrap().live
+  }
+
 }
 
-fn data_dir() -> Result<PathBuf> {
-  // 数据文件路径
+impl KvsEngine for KvStore {
+  fn set(&self, key: String, value: String) -> Result<()> {
+    KvStore::set(self, key, value)
+  }
+
+  fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+    KvStore::set_with_ttl(self, key, value, ttl)
+  }
+
+  fn get_versioned(&self, key: String) -> Result<Option<(String, u64)>> {
+    KvStore::get_versioned(self, key)
+  }
+
+  fn set_if_absent(&self, key: String, value: String) -> Result<u64> {
+    KvStore::set_if_absent(self, key, value)
+  }
+
+  fn set_if_equals(&self, key: String, expected: String, value: String) -> Result<u64> {
+    KvStore::set_if_equals(self, key, expected, value)
+  }
+
+  fn set_if_version(&self, key: String, version: u64, value: String) -> Result<u64> {
+    KvStore::set_if_version(self, key, version, value)
+  }
+
+  fn get(&self, key: String) -> Result<Option<String>> {
+    KvStore::get(self, key)
+  }
+
+  fn remove(&self, key: String) -> Result<()> {
+    KvStore::remove(self, key)
+  }
+
+  fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+    KvStore::write_batch(self, batch)
+  }
+
+  fn scan<R: RangeBounds<String>>(&self, range: R, limit: usize) -> Result<Vec<(String, String)>> {
+    KvStore::scan(self, range, limit)
+  }
+
+  fn keys(&self, prefix: &str) -> Result<Vec<String>> {
+    Ok(KvStore::keys(self, prefix))
+  }
+
+  fn keys_after(&self, prefix: &str, after: Option<&str>, limit: usize) -> Result<Vec<String>> {
+    Ok(KvStore::keys_after(self, prefix, after, limit))
+  }
+
+  fn count(&self, prefix: &str) -> Result<usize> {
+    Ok(KvStore::count(self, prefix))
+  }
+
+  fn compact(&self) -> Result<()> {
+    self.compact_now()
+  }
+}
+
+// 一组写入失败时每条指令都要返回错误，io错误不能clone，按类型和信息重新生成一个
+fn same_error(e: &KvError) -> KvError {
+  match e {
+    KvError::ReadOnly => KvError::ReadOnly,
+    KvError::Corruption(msg) => KvError::Corruption(msg.clone()),
+    KvError::Io(e) => KvError::Io(Error::new(e.kind(), e.to_string())),
+    e => KvError::Io(Error::other(e.to_string())),
+  }
+}
+
+// 每隔interval把还没落盘的数据fsync一次，KvStore都drop了之后线程退出
+fn spawn_syncer(writer: Weak<Mutex<KvWriter>>, interval: Duration) -> io::Result<()> {
+  thread::Builder::new()
+    .name("kv-syncer".to_string())
+    .spawn(move || {
+      loop {
+        thread::sleep(interval);
+        let Some(kv_writer) = writer.upgrade() else { break };
+        let synced = kv_writer.lock().unwrap().sync();
+        if let Err(e) = synced {
+          println!("数据文件fsync失败！{}", e);
+        }
+      }
+    })?;
+  Ok(())
+}
+
+fn data_dir() -> io::Result<PathBuf> {
+  // 默认的数据文件路径
   // current_dir/data
-  let data_path = current_dir()?.join("data");
-  // 创建目录
-  create_dir_all(&data_path)?;
-  Ok(data_path)
+  Ok(current_dir()?.join("data"))
 }
 
-fn sorted_file_names(data_path: &PathBuf) -> Result<Vec<u32>> {
+fn sorted_file_names(data_path: &Path) -> io::Result<Vec<u32>> {
   // 读取数据文件目录所有的文件，
   // 过滤，只要.log结尾的文件
   // 只要数字开头的文件
   let mut file_names: Vec<u32> = read_dir(data_path)?
     // 展开PathBuf
-    .flat_map(|res| Ok(res?.path()) as Result<PathBuf>)
+    .flat_map(|res| Ok(res?.path()) as io::Result<PathBuf>)
     // 过滤出.log文件 
     .filter(|res| res.is_file() && res.extension() == Some("log".as_ref()))
     // 从路径中取出文件名
@@ -227,115 +769,384 @@ fn sorted_file_names(data_path: &PathBuf) -> Result<Vec<u32>> {
     Ok(file_names)
 }
 
-fn data_file_path(path: &PathBuf, file_name: u32) -> Result<PathBuf> {
-  Ok(path.join(format!("{}.log", file_name)))
+fn data_file_path(path: &Path, file_name: u32) -> PathBuf {
+  path.join(format!("{}.log", file_name))
 }
 
-fn new_data_file(dir: &PathBuf, file_name: u32, readers: &mut HashMap<u32, BufReader<File>>) -> Result<WriterWithPos<File>> {
+fn new_data_file(dir: &Path, file_name: u32, readers: &Readers) -> io::Result<WriterWithPos<File>> {
+  open_data_file(&data_file_path(dir, file_name), file_name, readers)
+}
 
-  // 文件路径
-  let file_path = data_file_path(dir, file_name)?;
+// 打开file_path的数据文件用来追加写入，它的reader按file_name放进readers中
+// 压缩时先写到临时文件中，完成后再改名，所以路径不一定是file_name.log
+fn open_data_file(file_path: &Path, file_name: u32, readers: &Readers) -> io::Result<WriterWithPos<File>> {
 
   // writer, 文件已经创建
-  let writer = WriterWithPos::new(
+  let mut writer = WriterWithPos::new(
     OpenOptions::new()
     .create(true)
     .read(true)
-    .write(true)
     .append(true)
-    .open(&file_path)?
+    .open(file_path)?
   )?;
-  readers.insert(file_name, BufReader::new(File::open(file_path)?));
+  // 新文件先写入文件头，标明数据文件的格式版本
+  if writer.pos == 0 {
+    writer.write_all(&file_header())?;
+    writer.flush()?;
+  }
+  readers.insert(file_name, File::open(file_path)?, FileFormat::Record(FILE_VERSION));
 
   Ok(writer)
 }
 
-fn load_idx(dir: &PathBuf, 
+fn load_idx(dir: &Path, 
   file_names: Vec<u32>, 
-  readers: &mut HashMap<u32, BufReader<File>>, 
-  index: &mut BTreeMap<String, CmdIdx>) -> Result<u64> {
+  readers: &Readers, 
+  index: &mut BTreeMap<String, CmdIdx>,
+  recovery: RecoveryMode,
+  covered: Option<(u32, u64)>,
+  read_only: bool) -> io::Result<(u64, u64)> {
     let mut uncompacted = 0;
+    let mut max_version = 0;
+    let newest = file_names.last().cloned();
     // 从所有的数据文件中加载数据到索引中
     for file_name in file_names {
       // 每个文件的reader
-      let file = File::open(data_file_path(dir, file_name)?)?;
+      let file_path = data_file_path(dir, file_name);
+      let file = File::open(&file_path)?;
+      let file_len = file.metadata()?.len();
+      // 索引快照已经包含的数据不用回放，从快照覆盖到的位置开始
+      let from = match covered {
+        Some((covered_file, _)) if file_name < covered_file => file_len,
+        Some((covered_file, pos)) if file_name == covered_file => pos,
+        _ => 0,
+      };
+      // 压缩生成的数据文件有索引文件，直接用索引文件建索引，索引文件不能用时再回放数据文件
+      let hint = if from > 0 { Ok(None) } else { read_hint(dir, file_name, file_len) };
+      match hint {
+        Ok(Some(hint)) => {
+          max_version = max_version.max(hint.max_version);
+          for (key, cmd_idx) in hint.entries {
+            if let Some(cmd_old) = index.insert(key, cmd_idx) {
+              uncompacted += cmd_old.len;
+            }
+          }
+          readers.insert(file_name, file, FileFormat::Record(FILE_VERSION));
+          continue;
+        },
+        Ok(None) => (),
+        Err(e) => println!("{}.hint 不可用，回放数据文件：{}", file_name, e),
+      }
       let mut file_reader = BufReader::new(file);
-      uncompacted += load_idx_from_file(file_name, &mut file_reader, index)?;
+      let (format, replay) = load_idx_from_file(file_name, &mut file_reader, index, from)?;
+      uncompacted += replay.uncompacted;
+      max_version = max_version.max(replay.max_version);
+      if let Some(e) = replay.torn {
+        // 只有最新的数据文件末尾可能是写了一半的数据，其它文件出现这种情况就是数据损坏了
+        if recovery == RecoveryMode::Strict || Some(file_name) != newest {
+          return Err(e);
+        }
+        // 只读打开时不修改文件，可能是另一个进程正在写，只是不读这部分数据
+        if read_only {
+          println!("{}.log 末尾的数据不完整，忽略位置{}之后的{}字节：{}", file_name, replay.valid_len, file_len - replay.valid_len, e);
+        } else {
+          println!("{}.log 末尾的数据不完整，丢弃位置{}之后的{}字节：{}", file_name, replay.valid_len, file_len - replay.valid_len, e);
+          OpenOptions::new().write(true).open(&file_path)?.set_len(replay.valid_len)?;
+        }
+      }
       
       // 每个文件的reader都保存下来，get的时候，根据key找到索引，索引中有文件名和key对应的位置。
-      readers.insert(file_name, file_reader);
+      readers.insert(file_name, file_reader.into_inner(), format);
     }
-  Ok(uncompacted)
+  Ok((uncompacted, max_version))
+}
+
+// 回放一个数据文件的结果
+#[derive(Default)]
+struct Replay {
+  // 可以压缩的数据长度
+  uncompacted: u64,
+  // 回放到的最大的数据版本号
+  max_version: u64,
+  // 最后一条完整数据的结束位置
+  valid_len: u64,
+  // 文件末尾不完整的数据，valid_len之后的数据都不可用
+  torn: Option<Error>,
 }
 
+// 从一个数据文件的from位置开始回放索引，返回文件的格式和回放的结果
+// 文件中间的数据损坏直接返回错误，文件末尾不完整的数据放在结果中，由调用方决定怎么处理
 fn load_idx_from_file(file_name: u32, 
   file_reader: &mut BufReader<File>, 
-  index: &mut BTreeMap<String, CmdIdx>) -> Result<u64> {
-  let mut uncompacted = 0;
-  // 从文件开始位置读
-  let mut start_pos = file_reader.seek(SeekFrom::Start(0))?;
+  index: &mut BTreeMap<String, CmdIdx>,
+  from: u64) -> io::Result<(FileFormat, Replay)> {
+  // 从文件开始位置读，根据文件头判断格式
+  file_reader.seek(SeekFrom::Start(0))?;
+  let mut head = Vec::new();
+  file_reader.by_ref().take(FILE_HEADER_LEN).read_to_end(&mut head)?;
+  let format = FileFormat::detect(&head);
+  // 文件头都没有写完整
+  if format == FileFormat::Json && !head.is_empty() && (head.len() as u64) < FILE_HEADER_LEN && file_header().starts_with(&head) {
+    let torn = Error::new(ErrorKind::UnexpectedEof, format!("{}.log: 文件头不完整", file_name));
+    return Ok((format, Replay { torn: Some(torn), ..Default::default() }));
+  }
+  let replay = match format {
+    FileFormat::Json => load_idx_from_json(file_name, file_reader, index, from)?,
+    FileFormat::Record(FILE_VERSION) => load_idx_from_records(file_name, file_reader, index, from.max(FILE_HEADER_LEN))?,
+    FileFormat::Record(version) => {
+      return Err(corruption(format!("{}.log: 不支持的数据文件版本{}", file_name, version)));
+    },
+  };
+  Ok((format, replay))
+}
+
+// 旧格式的数据文件，json直接首尾相连
+fn load_idx_from_json(file_name: u32, 
+  file_reader: &mut BufReader<File>, 
+  index: &mut BTreeMap<String, CmdIdx>,
+  from: u64) -> io::Result<Replay> {
+  let mut replay = Replay::default();
+  // 从from位置开始读
+  let mut start_pos = file_reader.seek(SeekFrom::Start(from))?;
   // 按Command的json格式读
   let mut from_reader = Deserializer::from_reader(file_reader).into_iter::<Command>();
   while let Some(cmd) = from_reader.next() {
     // command的结束位置
-    let end_pos = from_reader.byte_offset() as u64;
-    match cmd? {
-      // 匹配到set命令
-      Command::Set { key, .. } => {
-        // 将数据的位置范围记录在Btreemap中
-        let cmd_index: CmdIdx = (file_name, Range {start: start_pos, end: end_pos}).into();
-        if let Some(cmd_old) = index.insert(key, cmd_index) {
-          // 将旧值长度累加
-          uncompacted += cmd_old.len;
+    let end_pos = from + from_reader.byte_offset() as u64;
+    let cmd = match cmd {
+      Ok(cmd) => cmd,
+      Err(e) => {
+        let err = Error::new(json_error_kind(&e), format!("{}.log 位置{}: {}", file_name, start_pos, e));
+        // json没有读完文件就结束了，说明是写了一半的数据
+        if e.is_eof() {
+          replay.torn = Some(err);
+          break;
         }
+        return Err(err);
+      },
+    };
+    replay.max_version = replay.max_version.max(cmd_version(&cmd));
+    replay.uncompacted += apply_cmd(index, cmd, (file_name, Range {start: start_pos, end: end_pos}).into());
+    // 开始位置就是下个命令的结束位置
+    start_pos = end_pos;
+  }
+  replay.valid_len = start_pos;
+  Ok(replay)
+}
+
+// json解析错误对应的io错误类型，数据不完整的是UnexpectedEof
+fn json_error_kind(e: &serde_json::Error) -> ErrorKind {
+  if e.is_eof() {
+    ErrorKind::UnexpectedEof
+  } else {
+    e.io_error_kind().unwrap_or(ErrorKind::InvalidData)
+  }
+}
+
+// 记录格式的数据文件，从from位置开始读，from在文件头之后
+fn load_idx_from_records(file_name: u32, 
+  file_reader: &mut BufReader<File>, 
+  index: &mut BTreeMap<String, CmdIdx>,
+  from: u64) -> io::Result<Replay> {
+  let mut replay = Replay::default();
+  let mut start_pos = file_reader.seek(SeekFrom::Start(from))?;
+  loop {
+    let (record, len) = match next_record(file_name, file_reader, start_pos)? {
+      NextRecord::Record(record, len) => (record, len),
+      NextRecord::End => break,
+      NextRecord::Torn(e) => {
+        replay.torn = Some(e);
+        break;
       },
-      // 匹配到remove命令
-      Command::Remove { key } => {
-        if let Some(cmd_old) = index.remove(&key) {
-          // 将旧值长度累加
-          uncompacted += cmd_old.len;  
+    };
+    let mut end_pos = start_pos + len;
+    // 一条指令，或者一批指令，都读完整了才回放
+    let mut cmds = Vec::new();
+    match record.kind {
+      RecordType::Command => cmds.push((record.to_command()?, start_pos..end_pos)),
+      RecordType::Batch => {
+        for _ in 0..record.batch_count()? {
+          let (record, len) = match next_record(file_name, file_reader, end_pos)? {
+            NextRecord::Record(record, len) if record.kind == RecordType::Command => (record, len),
+            NextRecord::Record(..) => {
+              return Err(corruption(format!("{}.log 位置{}: 批量写入中只能有指令记录", file_name, end_pos)));
+            },
+            // 批量写入写了一半，整批都不要，从开始记录的位置截断
+            NextRecord::End => {
+              replay.torn = Some(Error::new(ErrorKind::UnexpectedEof, format!("{}.log 位置{}: 批量写入不完整", file_name, start_pos)));
+              break;
+            },
+            NextRecord::Torn(e) => {
+              replay.torn = Some(e);
+              break;
+            },
+          };
+          cmds.push((record.to_command()?, end_pos..end_pos + len));
+          end_pos += len;
+        }
+        if replay.torn.is_some() {
+          break;
         }
-        // 刚才累加的set的长度，还需要把remove指令的长度也累加上
-        uncompacted += end_pos - start_pos;
+        // 开始记录本身可以压缩掉
+        replay.uncompacted += len;
       },
-      // get命令不会在数据文件中
-      _ => (),
     }
-    // 开始位置就是下个命令的结束位置
+    for (cmd, range) in cmds {
+      replay.max_version = replay.max_version.max(cmd_version(&cmd));
+      replay.uncompacted += apply_cmd(index, cmd, (file_name, range).into());
+    }
     start_pos = end_pos;
   }
-  Ok(uncompacted)
+  replay.valid_len = start_pos;
+  Ok(replay)
+}
+
+// 数据文件中读到的下一条记录
+enum NextRecord {
+  // 记录和它占用的字节数
+  Record(Record, u64),
+  // 正好读到文件末尾
+  End,
+  // 文件末尾写了一半的数据
+  Torn(Error),
+}
+
+// 从pos位置读下一条记录，文件中间的数据损坏返回错误
+fn next_record(file_name: u32, file_reader: &mut BufReader<File>, pos: u64) -> io::Result<NextRecord> {
+  // 每条记录都有crc校验，出错时能定位到具体的记录
+  // 记录头校验失败的，后面还有数据就是数据损坏，不会因为长度坏了就把后面的数据都当成不完整的记录
+  match Record::read_from(file_reader) {
+    Ok(Some((record, len))) => Ok(NextRecord::Record(record, len)),
+    Ok(None) => Ok(NextRecord::End),
+    Err(e) => {
+      let err = Error::new(e.kind(), format!("{}.log 位置{}: {}", file_name, pos, e));
+      // 记录不完整，或者校验失败的是最后一条记录，说明是写了一半的数据
+      if e.kind() == ErrorKind::UnexpectedEof || file_reader.fill_buf()?.is_empty() {
+        return Ok(NextRecord::Torn(err));
+      }
+      Err(err)
+    },
+  }
+}
+
+// 写入一组指令时，key在这条指令执行前是否存在：这一组中前面的指令动过它就看前面的结果，否则查索引，过期的不算
+fn key_exists(index: &BTreeMap<String, CmdIdx>, touched: &HashMap<String, bool>, key: &str, now: u64) -> bool {
+  touched
+    .get(key)
+    .cloned()
+    .unwrap_or_else(|| index.get(key).is_some_and(|cmd_idx| !cmd_idx.is_expired(now)))
+}
+
+// 写入成功的指令更新到内存索引中，累加可以合并的数据长度和有效数据长度
+fn update_index(writer: &mut KvWriter, index: &mut BTreeMap<String, CmdIdx>, cmd: Command, cmd_idx: CmdIdx) {
+  let is_set = matches!(cmd, Command::Set { .. });
+  let uncompacted = apply_cmd(index, cmd, cmd_idx);
+  writer.uncompacted += uncompacted;
+  // 被覆盖或者删除的旧数据不再有效，remove指令本身也不算有效数据
+  if is_set {
+    writer.live = writer.live + cmd_idx.len - uncompacted;
+  } else {
+    writer.live -= uncompacted - cmd_idx.len;
+  }
+}
+
+// 从索引中去掉now时已经过期的key，返回它们的数据长度
+fn remove_expired(index: &mut BTreeMap<String, CmdIdx>, now: u64) -> u64 {
+  let mut expired = 0;
+  index.retain(|_, cmd_idx| {
+    if cmd_idx.is_expired(now) {
+      expired += cmd_idx.len;
+      return false;
+    }
+    true
+  });
+  expired
+}
+
+// 指令中的数据版本号，没有版本号的是0
+fn cmd_version(cmd: &Command) -> u64 {
+  match cmd {
+    Command::Set { version, .. } => version.unwrap_or(0),
+    _ => 0,
+  }
+}
+
+// 回放一条指令到索引中，返回可以压缩的数据长度
+fn apply_cmd(index: &mut BTreeMap<String, CmdIdx>, cmd: Command, cmd_index: CmdIdx) -> u64 {
+  let mut uncompacted = 0;
+  match cmd {
+    // 匹配到set命令
+    Command::Set { key, expires_at, version, .. } => {
+      // 将数据的位置范围、过期时间和版本号记录在Btreemap中
+      if let Some(cmd_old) = index.insert(key, CmdIdx { expires_at, version: version.unwrap_or(0), ..cmd_index }) {
+        // 将旧值长度累加
+        uncompacted += cmd_old.len;
+      }
+    },
+    // 匹配到remove命令
+    Command::Remove { key } => {
+      if let Some(cmd_old) = index.remove(&key) {
+        // 将旧值长度累加
+        uncompacted += cmd_old.len;  
+      }
+      // 刚才累加的set的长度，还需要把remove指令的长度也累加上
+      uncompacted += cmd_index.len;
+    },
+    // get命令不会在数据文件中
+    _ => (),
+  }
+  uncompacted
 }
 
 #[cfg(test)]
 mod tests {
-  use std::{env::current_dir, fs::{File, OpenOptions}, io::{self, BufReader, Read, Result, Seek, Write}};
-use serde_json::Deserializer;
+  use std::{fs::{self, File, OpenOptions}, io::{self, BufReader, ErrorKind, Read, Seek, Write}, path::Path, thread, time::Duration};
+  use serde_json::Deserializer;
+  use tempfile::TempDir;
+
+  use crate::error::{ErrorCode, KvError, Result};
+
+  use super::{batch::WriteBatch, builder::{CompactionPolicy, Durability, RecoveryMode}, command::Command, record::{FILE_HEADER_LEN, RECORD_HEADER_LEN}, writer::WriterWithPos, KvStore};
 
-use super::{command::Command, writer::WriterWithPos, KvStore};
+  // 测试用的数据文件，内容是连续的json指令
+  fn data_log(dir: &Path) -> io::Result<File> {
+    let path = dir.join("data.log");
+    let mut file = File::create(&path)?;
+    for _ in 0..3 {
+      serde_json::to_writer(&mut file, &Command::set("key".to_string(), "value".to_string()))?;
+    }
+    File::open(path)
+  }
 
   #[test]
   fn test_set() -> Result<()> {
-    let mut kvs = KvStore::open()?;
-    let _ = kvs.set("key".to_string(), "value".to_string())?;
+    let dir = TempDir::new()?;
+    let kvs = KvStore::open_at(dir.path())?;
+    kvs.set("key".to_string(), "value".to_string())?;
     Ok(())
   }
 
   #[test]
   fn test_open_set() -> Result<()> {
-    let mut open = KvStore::open()?;
+    let dir = TempDir::new()?;
+    let open = KvStore::open_at(dir.path())?;
     let _ = open.set("foo".to_string(), "bar".to_string());
-    assert_eq!(1, open.index.len());
+    assert_eq!(1, open.index.read().unwrap().len());
     let _ = open.set("foo1".to_string(), "bar1".to_string());
-    assert_eq!(2, open.index.len());
+    assert_eq!(2, open.index.read().unwrap().len());
     let _ = open.set("foo2".to_string(), "bar2".to_string());
-    assert_eq!(3, open.index.len());
+    assert_eq!(3, open.index.read().unwrap().len());
     Ok(())
   }
 
   #[test]
   fn test_get() -> Result<()> {
-    let mut open = KvStore::open()?;
+    let dir = TempDir::new()?;
+    let open = KvStore::open_at(dir.path())?;
+    open.set("foo".to_string(), "bar".to_string())?;
+    drop(open);
+    // 重新打开，从数据文件中回放索引
+    let open = KvStore::open_at(dir.path())?;
     let get = open.get("foo".to_string())?;
     assert_eq!(Some("bar".to_string()), get);
     Ok(())
@@ -343,7 +1154,9 @@ use super::{command::Command, writer::WriterWithPos, KvStore};
 
   #[test]
   fn test_remove() -> Result<()> {
-    let mut open = KvStore::open()?;
+    let dir = TempDir::new()?;
+    let open = KvStore::open_at(dir.path())?;
+    open.set("foo1".to_string(), "bar1".to_string())?;
     let mut is_err = false;
     open.remove("foo1".to_string()).unwrap_or_else(|_| is_err = true);
     assert!(!is_err);
@@ -354,28 +1167,761 @@ use super::{command::Command, writer::WriterWithPos, KvStore};
 
   #[test]
   fn test_compact() -> Result<()> {
-    let mut open = KvStore::open()?;
+    let dir = TempDir::new()?;
+    let open = KvStore::open_at(dir.path())?;
     for i in 0..1000 {
         open.set("key-foo".to_string(), format!("value-bar-{}", i))?;
     }
     // open.remove(format!("key-foo"))?;
-    open.compact()?;
+    open.compact_now()?;
     assert_eq!("value-bar-999".to_string(), open.get("key-foo".to_string())?.expect("错误了。。"));
 
     Ok(())
   }
 
+  #[test]
+  fn test_open_with_torn_compaction() -> Result<()> {
+    let dir = TempDir::new()?;
+    let open = KvStore::open_at(dir.path())?;
+    for i in 0..10 {
+      open.set(format!("key{}", i), "value".to_string())?;
+    }
+    drop(open);
+    // 压缩到一半崩溃：压缩文件只写了一部分，新的写入已经切换到了更新的数据文件
+    let data = fs::read(dir.path().join("1.log"))?;
+    fs::write(dir.path().join("2.log.compact"), &data[..data.len() / 2])?;
+    fs::write(dir.path().join("3.log"), &data[..FILE_HEADER_LEN as usize])?;
+
+    let open = KvStore::open_at(dir.path())?;
+    assert_eq!(Some("value".to_string()), open.get("key9".to_string())?);
+    assert!(!dir.path().join("2.log.compact").exists());
+    // 压缩完成后不会留下临时文件
+    open.compact_now()?;
+    assert_eq!(10, open.count(""));
+    assert!(fs::read_dir(dir.path())?.all(|entry| entry.unwrap().path().extension() != Some("compact".as_ref())));
+    Ok(())
+  }
+
+  #[test]
+  fn test_compact_in_data_dir() -> Result<()> {
+    let dir = TempDir::new()?;
+    let other = TempDir::new()?;
+    // 两个store在同一个进程中，各用各的目录
+    let open = KvStore::builder(dir.path()).compaction_threshold(0).open()?;
+    let other_open = KvStore::open_at(other.path())?;
+    other_open.set("other".to_string(), "value".to_string())?;
+    for i in 0..10 {
+      open.set("key-foo".to_string(), format!("value-bar-{}", i))?;
+    }
+    drop(open);
+    // 压缩只会清理自己目录下的旧文件
+    assert_eq!(vec!["other".to_string()], other_open.index.read().unwrap().keys().cloned().collect::<Vec<_>>());
+    assert_eq!(Some("value".to_string()), other_open.get("other".to_string())?);
+    let open = KvStore::open_at(dir.path())?;
+    assert_eq!(Some("value-bar-9".to_string()), open.get("key-foo".to_string())?);
+    Ok(())
+  }
+
+  #[test]
+  fn test_concurrent_set_get() -> Result<()> {
+    let dir = TempDir::new()?;
+    let open = KvStore::open_at(dir.path())?;
+    let handles = (0..4).map(|t| {
+      let store = open.clone();
+      thread::spawn(move || -> Result<()> {
+        for i in 0..100 {
+          store.set(format!("key-{}-{}", t, i), format!("value-{}", i))?;
+          assert_eq!(Some(format!("value-{}", i)), store.get(format!("key-{}-{}", t, i))?);
+        }
+        Ok(())
+      })
+    }).collect::<Vec<_>>();
+    for handle in handles {
+      handle.join().unwrap()?;
+    }
+    assert_eq!(400, open.index.read().unwrap().len());
+    Ok(())
+  }
+
+  #[test]
+  fn test_get_during_compaction() -> Result<()> {
+    let dir = TempDir::new()?;
+    let open = KvStore::builder(dir.path()).compaction_threshold(256).open()?;
+    open.set("fixed".to_string(), "value".to_string())?;
+    // 一边不停的写触发压缩，一边读，读到的值始终是对的
+    let reader = open.clone();
+    let handle = thread::spawn(move || -> Result<()> {
+      for _ in 0..1000 {
+        assert_eq!(Some("value".to_string()), reader.get("fixed".to_string())?);
+      }
+      Ok(())
+    });
+    for i in 0..1000 {
+      open.set("key-foo".to_string(), format!("value-bar-{}", i))?;
+    }
+    handle.join().unwrap()?;
+    Ok(())
+  }
+
+  #[test]
+  fn test_background_compaction() -> Result<()> {
+    let dir = TempDir::new()?;
+    let open = KvStore::builder(dir.path()).compaction_threshold(1024).open()?;
+    // 多个线程不停覆盖同一批key，压缩在后台进行，期间写入的数据不能丢
+    let handles = (0..4).map(|t| {
+      let store = open.clone();
+      thread::spawn(move || -> Result<()> {
+        for i in 0..500 {
+          store.set(format!("key-{}-{}", t, i % 10), format!("value-{}", i))?;
+          if i % 50 == 0 {
+            store.remove(format!("key-{}-{}", t, i % 10))?;
+          }
+        }
+        Ok(())
+      })
+    }).collect::<Vec<_>>();
+    for handle in handles {
+      handle.join().unwrap()?;
+    }
+    for t in 0..4 {
+      for k in 0..10 {
+        assert_eq!(Some(format!("value-{}", 490 + k)), open.get(format!("key-{}-{}", t, k))?);
+      }
+    }
+    // drop的时候等后台压缩结束
+    drop(open);
+    let log_files = fs::read_dir(dir.path())?.count();
+    assert!(log_files < 10, "压缩后还有{}个数据文件", log_files);
+    let open = KvStore::open_at(dir.path())?;
+    assert_eq!(40, open.index.read().unwrap().len());
+    assert_eq!(Some("value-499".to_string()), open.get("key-3-9".to_string())?);
+    Ok(())
+  }
+
+  #[test]
+  fn test_open_legacy_json_log() -> Result<()> {
+    let dir = TempDir::new()?;
+    // 旧格式的数据文件
+    let mut file = File::create(dir.path().join("1.log"))?;
+    serde_json::to_writer(&mut file, &Command::set("foo".to_string(), "bar".to_string()))?;
+    serde_json::to_writer(&mut file, &Command::set("foo1".to_string(), "bar1".to_string()))?;
+    serde_json::to_writer(&mut file, &Command::Remove { key: "foo1".to_string() })?;
+    drop(file);
+
+    let open = KvStore::open_at(dir.path())?;
+    assert_eq!(Some("bar".to_string()), open.get("foo".to_string())?);
+    assert_eq!(None, open.get("foo1".to_string())?);
+    open.set("foo2".to_string(), "bar2".to_string())?;
+    // 压缩后旧格式的数据转成了新格式
+    open.compact_now()?;
+    drop(open);
+    assert!(!dir.path().join("1.log").exists());
+    let open = KvStore::open_at(dir.path())?;
+    assert_eq!(Some("bar".to_string()), open.get("foo".to_string())?);
+    assert_eq!(Some("bar2".to_string()), open.get("foo2".to_string())?);
+    Ok(())
+  }
+
+  #[test]
+  fn test_corrupted_record() -> Result<()> {
+    let dir = TempDir::new()?;
+    let open = KvStore::open_at(dir.path())?;
+    open.set("foo".to_string(), "bar".to_string())?;
+    open.set("foo1".to_string(), "bar1".to_string())?;
+    drop(open);
+
+    // 改掉第一条记录中的一个字节
+    let path = dir.path().join("1.log");
+    let mut data = fs::read(&path)?;
+    data[FILE_HEADER_LEN as usize + RECORD_HEADER_LEN as usize + 2] ^= 0xff;
+    fs::write(&path, data)?;
+
+    let err = KvStore::open_at(dir.path()).err().expect("数据损坏应该打开失败");
+    assert_eq!(ErrorCode::Corruption, err.code());
+    assert!(err.to_string().contains("1.log"));
+    Ok(())
+  }
+
+  #[test]
+  fn test_corrupted_record_length() -> Result<()> {
+    let dir = TempDir::new()?;
+    let open = KvStore::open_at(dir.path())?;
+    for i in 0..5 {
+      open.set(format!("key{}", i), "value".to_string())?;
+    }
+    drop(open);
+
+    // 改掉第一条记录的长度，按这个长度读到文件末尾也不够，但不能当成末尾写了一半的记录截掉
+    let path = dir.path().join("1.log");
+    let mut data = fs::read(&path)?;
+    data[FILE_HEADER_LEN as usize + 4] ^= 0x01;
+    fs::write(&path, &data)?;
+
+    let err = KvStore::open_at(dir.path()).err().expect("数据损坏应该打开失败");
+    assert_eq!(ErrorCode::Corruption, err.code());
+    assert_eq!(data.len() as u64, fs::metadata(&path)?.len());
+    Ok(())
+  }
+
+  #[test]
+  fn test_open_with_hint() -> Result<()> {
+    let dir = TempDir::new()?;
+    let open = KvStore::open_at(dir.path())?;
+    open.set("foo".to_string(), "bar".to_string())?;
+    open.set("foo1".to_string(), "bar1".to_string())?;
+    open.compact_now()?;
+    drop(open);
+    // 压缩文件是2.log，旁边有它的索引文件
+    let hint_path = dir.path().join("2.hint");
+    let hint = fs::read(&hint_path)?;
+
+    // 索引文件损坏，回放数据文件
+    let mut corrupted = hint.clone();
+    corrupted[10] ^= 0xff;
+    fs::write(&hint_path, corrupted)?;
+    let open = KvStore::open_at(dir.path())?;
+    assert_eq!(Some("bar".to_string()), open.get("foo".to_string())?);
+    assert_eq!(Some("bar1".to_string()), open.get("foo1".to_string())?);
+    drop(open);
+
+    // 索引文件可用时不会回放数据文件，数据文件中value损坏也能打开
+    fs::write(&hint_path, &hint)?;
+    let log_path = dir.path().join("2.log");
+    let mut data = fs::read(&log_path)?;
+    data[FILE_HEADER_LEN as usize + RECORD_HEADER_LEN as usize + 2] ^= 0xff;
+    fs::write(&log_path, data)?;
+    let open = KvStore::open_at(dir.path())?;
+    assert_eq!(0, open.uncompacted());
+    assert_eq!(Some("bar1".to_string()), open.get("foo1".to_string())?);
+    drop(open);
+
+    // 没有索引文件时回放，发现数据损坏
+    fs::remove_file(&hint_path)?;
+    assert_eq!(ErrorCode::Corruption, KvStore::open_at(dir.path()).err().expect("数据损坏应该打开失败").code());
+    Ok(())
+  }
+
+  #[test]
+  fn test_open_locked_dir() -> Result<()> {
+    let dir = TempDir::new()?;
+    let open = KvStore::open_at(dir.path())?;
+    let err = KvStore::open_at(dir.path()).err().expect("数据目录已经被打开");
+    assert!(matches!(&err, KvError::Io(e) if e.kind() == ErrorKind::ResourceBusy));
+    assert!(err.to_string().contains(&std::process::id().to_string()));
+    // clone出来的都drop之后才释放
+    let cloned = open.clone();
+    drop(open);
+    assert!(KvStore::open_at(dir.path()).is_err());
+    drop(cloned);
+    KvStore::open_at(dir.path())?;
+    Ok(())
+  }
+
+  #[test]
+  fn test_read_only() -> Result<()> {
+    let dir = TempDir::new()?;
+    // 目录不存在时不会创建
+    let missing = dir.path().join("missing");
+    assert!(KvStore::builder(&missing).read_only(true).open().is_err());
+    assert!(!missing.exists());
+
+    let open = KvStore::open_at(dir.path())?;
+    open.set("foo".to_string(), "bar".to_string())?;
+    let files = fs::read_dir(dir.path())?.count();
+    // 可以和正在写的KvStore同时打开
+    let read_only = KvStore::builder(dir.path()).read_only(true).checkpoint_interval(Duration::from_millis(10)).open()?;
+    assert!(read_only.is_read_only());
+    // 不会启动压缩和写索引快照的后台线程
+    assert!(read_only.compactor.is_none());
+    assert!(read_only._checkpointer.is_none());
+    assert_eq!(Some("bar".to_string()), read_only.get("foo".to_string())?);
+    for err in [
+      read_only.set("foo".to_string(), "baz".to_string()),
+      read_only.remove("foo".to_string()),
+      read_only.remove("missing".to_string()),
+      read_only.compact_now(),
+      read_only.checkpoint_now(),
+    ] {
+      assert!(matches!(err, Err(KvError::ReadOnly)));
+    }
+    assert_eq!(files, fs::read_dir(dir.path())?.count());
+    assert_eq!(Some("bar".to_string()), open.get("foo".to_string())?);
+    Ok(())
+  }
+
+  #[test]
+  fn test_read_only_torn_tail() -> Result<()> {
+    let dir = TempDir::new()?;
+    let torn_len = torn_tail_store(dir.path())?;
+    let open = KvStore::builder(dir.path()).read_only(true).open()?;
+    assert_eq!(Some("bar".to_string()), open.get("foo".to_string())?);
+    assert_eq!(None, open.get("foo1".to_string())?);
+    // 只读打开不截断文件
+    assert_eq!(torn_len, fs::metadata(dir.path().join("1.log"))?.len());
+    Ok(())
+  }
+
+  #[test]
+  fn test_open_with_checkpoint() -> Result<()> {
+    let dir = TempDir::new()?;
+    let open = KvStore::open_at(dir.path())?;
+    open.set("foo".to_string(), "bar".to_string())?;
+    open.set("foo".to_string(), "baz".to_string())?;
+    open.checkpoint_now()?;
+    // 快照之后写入的数据
+    open.set("foo1".to_string(), "bar1".to_string())?;
+    open.remove("foo".to_string())?;
+    let uncompacted = open.uncompacted();
+    drop(open);
+
+    // 快照已经包含的数据不会回放，第一条记录损坏也能打开
+    let log_path = dir.path().join("1.log");
+    let data = fs::read(&log_path)?;
+    let mut corrupted = data.clone();
+    corrupted[FILE_HEADER_LEN as usize + RECORD_HEADER_LEN as usize + 2] ^= 0xff;
+    fs::write(&log_path, corrupted)?;
+    let open = KvStore::open_at(dir.path())?;
+    assert_eq!(None, open.get("foo".to_string())?);
+    assert_eq!(Some("bar1".to_string()), open.get("foo1".to_string())?);
+    assert_eq!(uncompacted, open.uncompacted());
+    drop(open);
+
+    // 快照损坏时回放所有数据文件
+    let checkpoint_path = dir.path().join("index.checkpoint");
+    let mut checkpoint = fs::read(&checkpoint_path)?;
+    checkpoint[10] ^= 0xff;
+    fs::write(&checkpoint_path, checkpoint)?;
+    assert_eq!(ErrorCode::Corruption, KvStore::open_at(dir.path()).err().expect("数据损坏应该打开失败").code());
+    fs::write(&log_path, data)?;
+    let open = KvStore::open_at(dir.path())?;
+    assert_eq!(Some("bar1".to_string()), open.get("foo1".to_string())?);
+    assert_eq!(uncompacted, open.uncompacted());
+    Ok(())
+  }
+
+  #[test]
+  fn test_stale_checkpoint() -> Result<()> {
+    let dir = TempDir::new()?;
+    let open = KvStore::builder(dir.path()).checkpoint_interval(Duration::from_millis(10)).open()?;
+    open.set("foo".to_string(), "bar".to_string())?;
+    thread::sleep(Duration::from_millis(100));
+    assert!(dir.path().join("index.checkpoint").exists());
+    drop(open);
+
+    let open = KvStore::open_at(dir.path())?;
+    open.checkpoint_now()?;
+    // 快照之后压缩过，快照中的数据文件已经删掉了
+    open.set("foo".to_string(), "baz".to_string())?;
+    open.compact_now()?;
+    open.set("foo1".to_string(), "bar1".to_string())?;
+    drop(open);
+    let open = KvStore::open_at(dir.path())?;
+    assert_eq!(Some("baz".to_string()), open.get("foo".to_string())?);
+    assert_eq!(Some("bar1".to_string()), open.get("foo1".to_string())?);
+    Ok(())
+  }
+
+  // 写两条数据，然后把最新数据文件的最后一条记录截掉一半
+  fn torn_tail_store(dir: &Path) -> Result<u64> {
+    let open = KvStore::open_at(dir)?;
+    open.set("foo".to_string(), "bar".to_string())?;
+    open.set("foo1".to_string(), "bar1".to_string())?;
+    drop(open);
+    let path = dir.join("1.log");
+    let len = fs::metadata(&path)?.len();
+    OpenOptions::new().write(true).open(&path)?.set_len(len - 5)?;
+    Ok(len - 5)
+  }
+
+  #[test]
+  fn test_recover_torn_tail() -> Result<()> {
+    let dir = TempDir::new()?;
+    let torn_len = torn_tail_store(dir.path())?;
+
+    let open = KvStore::open_at(dir.path())?;
+    assert_eq!(Some("bar".to_string()), open.get("foo".to_string())?);
+    assert_eq!(None, open.get("foo1".to_string())?);
+    // 不完整的记录被截掉了
+    assert!(fs::metadata(dir.path().join("1.log"))?.len() < torn_len);
+    open.set("foo1".to_string(), "bar2".to_string())?;
+    drop(open);
+
+    let open = KvStore::open_at(dir.path())?;
+    assert_eq!(Some("bar2".to_string()), open.get("foo1".to_string())?);
+    Ok(())
+  }
+
+  #[test]
+  fn test_strict_torn_tail() -> Result<()> {
+    let dir = TempDir::new()?;
+    let torn_len = torn_tail_store(dir.path())?;
+
+    let err = KvStore::builder(dir.path()).recovery(RecoveryMode::Strict).open().err().expect("严格模式应该打开失败");
+    assert!(matches!(&err, KvError::Io(e) if e.kind() == ErrorKind::UnexpectedEof));
+    // 文件没有被修改
+    assert_eq!(torn_len, fs::metadata(dir.path().join("1.log"))?.len());
+    Ok(())
+  }
+
+  #[test]
+  fn test_durability() -> Result<()> {
+    for durability in [Durability::Always, Durability::Interval(Duration::from_millis(10)), Durability::Never] {
+      let dir = TempDir::new()?;
+      let open = KvStore::builder(dir.path()).durability(durability).compaction_threshold(256).open()?;
+      for i in 0..100 {
+        open.set("key-foo".to_string(), format!("value-bar-{}", i))?;
+      }
+      open.remove("key-foo".to_string())?;
+      open.set("foo".to_string(), "bar".to_string())?;
+      thread::sleep(Duration::from_millis(20));
+      drop(open);
+      let open = KvStore::open_at(dir.path())?;
+      assert_eq!(None, open.get("key-foo".to_string())?);
+      assert_eq!(Some("bar".to_string()), open.get("foo".to_string())?);
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn test_group_commit() -> Result<()> {
+    let dir = TempDir::new()?;
+    let open = KvStore::builder(dir.path()).durability(Durability::Always).open()?;
+    let handles = (0..8).map(|t| {
+      let store = open.clone();
+      thread::spawn(move || -> Result<()> {
+        for i in 0..20 {
+          store.set(format!("key-{}-{}", t, i), format!("value-{}", i))?;
+          store.remove(format!("key-{}-{}", t, i))?;
+          // 已经删除了，不管和谁一起提交都应该返回NotFound
+          assert!(matches!(store.remove(format!("key-{}-{}", t, i)), Err(KvError::KeyNotFound)));
+          store.set(format!("key-{}-{}", t, i), format!("value-{}", i))?;
+        }
+        Ok(())
+      })
+    }).collect::<Vec<_>>();
+    for handle in handles {
+      handle.join().unwrap()?;
+    }
+    drop(open);
+    let open = KvStore::open_at(dir.path())?;
+    assert_eq!(160, open.index.read().unwrap().len());
+    assert_eq!(Some("value-19".to_string()), open.get("key-7-19".to_string())?);
+    Ok(())
+  }
+
+  #[test]
+  fn test_parse_durability() {
+    assert_eq!(Ok(Durability::Always), "always".parse());
+    assert_eq!(Ok(Durability::Never), "never".parse());
+    assert_eq!(Ok(Durability::Interval(Duration::from_millis(100))), "100ms".parse());
+    assert!("sometimes".parse::<Durability>().is_err());
+  }
+
+  #[test]
+  fn test_parse_compaction_policy() {
+    assert_eq!(Ok(CompactionPolicy::Manual), "manual".parse());
+    assert_eq!(Ok(CompactionPolicy::Bytes(4096)), "bytes:4096".parse());
+    assert_eq!(Ok(CompactionPolicy::Ratio(1.5)), "ratio:1.5".parse());
+    assert!("ratio:-1".parse::<CompactionPolicy>().is_err());
+    assert!("bytes".parse::<CompactionPolicy>().is_err());
+    assert!(CompactionPolicy::Ratio(1.0).should_compact(101, 100));
+    assert!(!CompactionPolicy::Ratio(1.0).should_compact(100, 100));
+  }
+
+  #[test]
+  fn test_scan() -> Result<()> {
+    let dir = TempDir::new()?;
+    let open = KvStore::builder(dir.path()).compaction_policy(CompactionPolicy::Manual).open()?;
+    for i in (0..10).rev() {
+      open.set(format!("key-{}", i), format!("value-{}", i))?;
+    }
+    open.set("key-3".to_string(), "new".to_string())?;
+    open.remove("key-5".to_string())?;
+
+    let keys = |pairs: Vec<(String, String)>| pairs.into_iter().map(|(key, _)| key).collect::<Vec<_>>();
+    let all = open.scan(.., usize::MAX)?;
+    assert_eq!(9, all.len());
+    assert_eq!(("key-0".to_string(), "value-0".to_string()), all[0]);
+    assert_eq!(("key-3".to_string(), "new".to_string()), all[3]);
+    assert_eq!(vec!["key-2", "key-3", "key-4", "key-6"], keys(open.scan("key-2".to_string().."key-7".to_string(), 4)?));
+    assert_eq!(vec!["key-8", "key-9"], keys(open.scan("key-8".to_string()..="key-9".to_string(), 10)?));
+    assert!(open.scan("key-9".to_string().."key-0".to_string(), 10)?.is_empty());
+
+    // 压缩后数据文件变了，扫描的结果不变
+    open.compact_now()?;
+    assert_eq!(all, open.scan(.., usize::MAX)?);
+    Ok(())
+  }
+
+  #[test]
+  fn test_keys() -> Result<()> {
+    let dir = TempDir::new()?;
+    let open = KvStore::open_at(dir.path())?;
+    for key in ["tenant1/obj1/a", "tenant1/obj1/b", "tenant1/obj2/a", "tenant2/obj1/a"] {
+      open.set(key.to_string(), "value".to_string())?;
+    }
+    open.remove("tenant1/obj1/b".to_string())?;
+    assert_eq!(vec!["tenant1/obj1/a", "tenant1/obj2/a"], open.keys("tenant1/"));
+    assert_eq!(2, open.count("tenant1/"));
+    assert_eq!(1, open.count("tenant1/obj1/"));
+    assert_eq!(0, open.count("tenant3/"));
+    assert_eq!(3, open.keys("").len());
+    // 分批取，从上一批的最后一个key后面接着取
+    assert_eq!(vec!["tenant1/obj1/a"], open.keys_after("tenant1/", None, 1));
+    assert_eq!(vec!["tenant1/obj2/a"], open.keys_after("tenant1/", Some("tenant1/obj1/a"), 1));
+    assert!(open.keys_after("tenant1/", Some("tenant1/obj2/a"), 1).is_empty());
+    Ok(())
+  }
+
+  #[test]
+  fn test_ttl() -> Result<()> {
+    let dir = TempDir::new()?;
+    let open = KvStore::builder(dir.path()).compaction_policy(CompactionPolicy::Manual).open()?;
+    open.set_with_ttl("expired".to_string(), "value".to_string(), Duration::ZERO)?;
+    open.set_with_ttl("alive".to_string(), "value".to_string(), Duration::from_secs(3600))?;
+    open.set("forever".to_string(), "value".to_string())?;
+    // 过期的key读不到，也不能remove
+    assert_eq!(None, open.get("expired".to_string())?);
+    assert_eq!(Some("value".to_string()), open.get("alive".to_string())?);
+    assert_eq!(vec!["alive", "forever"], open.keys(""));
+    assert_eq!(2, open.scan(.., 10)?.len());
+    assert!(matches!(open.remove("expired".to_string()), Err(KvError::KeyNotFound)));
+
+    // 重新打开时回放数据文件，过期的key不进索引，算作可以压缩的数据
+    let expired_len = open.index.read().unwrap()["expired"].len;
+    drop(open);
+    let open = KvStore::builder(dir.path()).compaction_policy(CompactionPolicy::Manual).open()?;
+    assert!(!open.index.read().unwrap().contains_key("expired"));
+    assert_eq!(expired_len, open.uncompacted());
+    let expires_at = open.index.read().unwrap()["alive"].expires_at;
+    assert!(expires_at.is_some());
+
+    // 压缩后过期时间还在，重新打开时从索引文件中读出来也还在
+    open.set_with_ttl("expired".to_string(), "value".to_string(), Duration::ZERO)?;
+    open.compact_now()?;
+    assert!(!open.index.read().unwrap().contains_key("expired"));
+    assert_eq!(expires_at, open.index.read().unwrap()["alive"].expires_at);
+    let live = open.live_size();
+    drop(open);
+    let open = KvStore::open_at(dir.path())?;
+    assert_eq!(expires_at, open.index.read().unwrap()["alive"].expires_at);
+    assert_eq!(0, open.uncompacted());
+    assert_eq!(live, open.live_size());
+    Ok(())
+  }
+
+  #[test]
+  fn test_conditional_set() -> Result<()> {
+    let dir = TempDir::new()?;
+    let open = KvStore::open_at(dir.path())?;
+    let version = open.set_if_absent("key".to_string(), "a".to_string())?;
+    assert_eq!(Some(("a".to_string(), version)), open.get_versioned("key".to_string())?);
+    assert_eq!(ErrorCode::ConditionFailed, open.set_if_absent("key".to_string(), "b".to_string()).unwrap_err().code());
+    assert!(matches!(open.set_if_equals("key".to_string(), "b".to_string(), "c".to_string()), Err(KvError::ConditionFailed)));
+    assert!(matches!(open.set_if_version("key".to_string(), version + 1, "c".to_string()), Err(KvError::ConditionFailed)));
+    let next = open.set_if_version("key".to_string(), version, "c".to_string())?;
+    assert!(next > version);
+    let version = open.set_if_equals("key".to_string(), "c".to_string(), "d".to_string())?;
+    // 过期的key和不存在的一样
+    open.set_with_ttl("expired".to_string(), "value".to_string(), Duration::ZERO)?;
+    open.set_if_absent("expired".to_string(), "value".to_string())?;
+
+    // 多个线程同时用版本号做自增，每次都读到最新的才能写成功，最后不会丢失更新
+    open.set("counter".to_string(), "0".to_string())?;
+    let handles = (0..4).map(|_| {
+      let store = open.clone();
+      thread::spawn(move || -> Result<()> {
+        for _ in 0..25 {
+          loop {
+            let (value, version) = store.get_versioned("counter".to_string())?.unwrap();
+            let next = (value.parse::<u32>().unwrap() + 1).to_string();
+            match store.set_if_version("counter".to_string(), version, next) {
+              Ok(_) => break,
+              Err(KvError::ConditionFailed) => continue,
+              Err(e) => return Err(e),
+            }
+          }
+        }
+        Ok(())
+      })
+    }).collect::<Vec<_>>();
+    for handle in handles {
+      handle.join().unwrap()?;
+    }
+    assert_eq!(Some("100".to_string()), open.get("counter".to_string())?);
+
+    // 版本号保存在数据文件中，重新打开后不变，删除的key的版本号也不会再分配
+    open.remove("counter".to_string())?;
+    let max_version = open.writer.lock().unwrap().version;
+    drop(open);
+    let open = KvStore::open_at(dir.path())?;
+    assert_eq!(Some(("d".to_string(), version)), open.get_versioned("key".to_string())?);
+    assert_eq!(max_version, open.writer.lock().unwrap().version);
+    // 压缩后删除的key的数据没有了，从索引文件中记下的版本号之后继续分配
+    open.compact_now()?;
+    drop(open);
+    let open = KvStore::open_at(dir.path())?;
+    assert_eq!(Some(("d".to_string(), version)), open.get_versioned("key".to_string())?);
+    assert!(open.set_if_absent("counter".to_string(), "0".to_string())? > max_version);
+    Ok(())
+  }
+
+  #[test]
+  fn test_write_batch() -> Result<()> {
+    let dir = TempDir::new()?;
+    let open = KvStore::open_at(dir.path())?;
+    open.set("from".to_string(), "100".to_string())?;
+    let batch = WriteBatch::new()
+      .remove("from".to_string())
+      .set("to".to_string(), "100".to_string())
+      .set("tmp".to_string(), "1".to_string())
+      .remove("tmp".to_string());
+    open.write_batch(batch)?;
+    assert_eq!(None, open.get("from".to_string())?);
+    assert_eq!(Some("100".to_string()), open.get("to".to_string())?);
+    assert_eq!(None, open.get("tmp".to_string())?);
+    // 有一个remove的key不存在，整批都不写入
+    let len = fs::metadata(dir.path().join("1.log"))?.len();
+    let batch = WriteBatch::new().set("other".to_string(), "1".to_string()).remove("from".to_string());
+    assert!(matches!(open.write_batch(batch), Err(KvError::KeyNotFound)));
+    assert_eq!(None, open.get("other".to_string())?);
+    assert_eq!(len, fs::metadata(dir.path().join("1.log"))?.len());
+    let (_, version) = open.get_versioned("to".to_string())?.unwrap();
+    drop(open);
+
+    // 重新打开回放批量写入，版本号也恢复了
+    let open = KvStore::open_at(dir.path())?;
+    assert_eq!(Some(("100".to_string(), version)), open.get_versioned("to".to_string())?);
+    assert_eq!(vec!["to"], open.keys(""));
+    // 压缩之后批量写入的数据还在
+    open.compact_now()?;
+    assert_eq!(Some(("100".to_string(), version)), open.get_versioned("to".to_string())?);
+    assert_eq!(0, open.uncompacted());
+    Ok(())
+  }
+
+  #[test]
+  fn test_torn_batch() -> Result<()> {
+    let dir = TempDir::new()?;
+    let open = KvStore::open_at(dir.path())?;
+    open.set("a".to_string(), "1".to_string())?;
+    let valid_len = fs::metadata(dir.path().join("1.log"))?.len();
+    open.write_batch(WriteBatch::new().remove("a".to_string()).set("b".to_string(), "2".to_string()))?;
+    drop(open);
+    // 批量写入的最后一条记录写了一半
+    let path = dir.path().join("1.log");
+    let len = fs::metadata(&path)?.len();
+    OpenOptions::new().write(true).open(&path)?.set_len(len - 5)?;
+
+    // 整批丢弃，前面的remove也不生效
+    let open = KvStore::open_at(dir.path())?;
+    assert_eq!(Some("1".to_string()), open.get("a".to_string())?);
+    assert_eq!(None, open.get("b".to_string())?);
+    assert_eq!(valid_len, fs::metadata(&path)?.len());
+    Ok(())
+  }
+
+  #[test]
+  fn test_rollback_failed_write() -> Result<()> {
+    let dir = TempDir::new()?;
+    let open = KvStore::open_at(dir.path())?;
+    open.set("a".to_string(), "1".to_string())?;
+    let valid_len = fs::metadata(dir.path().join("1.log"))?.len();
+    // 写了几个字节就失败了
+    open.writer.lock().unwrap().data_file()?.fail_at = Some(valid_len + 5);
+    assert!(matches!(open.set("b".to_string(), "2".to_string()), Err(KvError::Io(_))));
+    assert_eq!(valid_len, fs::metadata(dir.path().join("1.log"))?.len());
+    assert_eq!(None, open.get("b".to_string())?);
+
+    // 之后的写入接在回滚的位置后面，重新打开后都能读到
+    open.writer.lock().unwrap().data_file()?.fail_at = None;
+    open.set("c".to_string(), "3".to_string())?;
+    drop(open);
+    let open = KvStore::open_at(dir.path())?;
+    assert_eq!(Some("1".to_string()), open.get("a".to_string())?);
+    assert_eq!(None, open.get("b".to_string())?);
+    assert_eq!(Some("3".to_string()), open.get("c".to_string())?);
+    Ok(())
+  }
+
+  #[test]
+  fn test_rollback_failed_batch() -> Result<()> {
+    let dir = TempDir::new()?;
+    let open = KvStore::open_at(dir.path())?;
+    open.set("a".to_string(), "1".to_string())?;
+    let valid_len = fs::metadata(dir.path().join("1.log"))?.len();
+    // 开始记录写完了，第一条指令写了一半
+    open.writer.lock().unwrap().data_file()?.fail_at = Some(valid_len + RECORD_HEADER_LEN + 4 + 5);
+    let batch = WriteBatch::new().remove("a".to_string()).set("b".to_string(), "2".to_string());
+    assert!(matches!(open.write_batch(batch), Err(KvError::Io(_))));
+    assert_eq!(valid_len, fs::metadata(dir.path().join("1.log"))?.len());
+    assert_eq!(Some("1".to_string()), open.get("a".to_string())?);
+
+    // 之后的写入不会被当成那一批中的数据丢掉
+    open.writer.lock().unwrap().data_file()?.fail_at = None;
+    open.set("c".to_string(), "3".to_string())?;
+    drop(open);
+    let open = KvStore::open_at(dir.path())?;
+    assert_eq!(Some("1".to_string()), open.get("a".to_string())?);
+    assert_eq!(None, open.get("b".to_string())?);
+    assert_eq!(Some("3".to_string()), open.get("c".to_string())?);
+    Ok(())
+  }
+
+  #[test]
+  fn test_manual_compaction() -> Result<()> {
+    let dir = TempDir::new()?;
+    let open = KvStore::builder(dir.path()).compaction_policy(CompactionPolicy::Manual).open()?;
+    for i in 0..200 {
+      open.set("key".to_string(), format!("value-{}", i))?;
+    }
+    open.set("other".to_string(), "value".to_string())?;
+    open.remove("other".to_string())?;
+    // 手动模式下不会自动压缩，所有的旧数据都还在
+    let live = open.live_size();
+    assert_eq!(live, open.index.read().unwrap()["key"].len);
+    assert!(open.uncompacted() > 100 * live);
+
+    open.compact_now()?;
+    assert_eq!(0, open.uncompacted());
+    assert_eq!(live, open.live_size());
+    assert_eq!(Some("value-199".to_string()), open.get("key".to_string())?);
+    assert_eq!(None, open.get("other".to_string())?);
+    // 重新打开后统计的数据一致
+    drop(open);
+    let open = KvStore::open_at(dir.path())?;
+    assert_eq!(0, open.uncompacted());
+    assert_eq!(live, open.live_size());
+    Ok(())
+  }
+
+  #[test]
+  fn test_compaction_min_interval() -> Result<()> {
+    let dir = TempDir::new()?;
+    let open = KvStore::builder(dir.path())
+      .compaction_threshold(0)
+      .compaction_min_interval(Duration::from_secs(3600))
+      .open()?;
+    open.compact_now()?;
+    // 离上次压缩不到一小时，不会自动压缩
+    for i in 0..100 {
+      open.set("key".to_string(), format!("value-{}", i))?;
+    }
+    drop(open);
+    let open = KvStore::open_at(dir.path())?;
+    assert!(open.uncompacted() > 0);
+    Ok(())
+  }
+
   #[test]
   fn test_json_reader() -> Result<()> {
-    let join = current_dir()?.join("data.log");
-    let file = File::open(join)?;
-    let reader = BufReader::new(file);
+    let dir = TempDir::new()?;
+    let reader = BufReader::new(data_log(dir.path())?);
     let from_reader = Deserializer::from_reader(reader);
-    let mut stream_deserializer = from_reader.into_iter::<Command>();
+    let stream_deserializer = from_reader.into_iter::<Command>();
 
-    while let Some(cmd) = stream_deserializer.next() {
-      // let byte_offset = stream_deserializer.byte_offset() as u64;
-      if let Command::Set { key, value } = cmd? {
+    for cmd in stream_deserializer {
+      if let Command::Set { key, value, .. } = cmd? {
           assert_eq!("key", key);
           assert_eq!("value", value);
       }
@@ -386,8 +1932,9 @@ use super::{command::Command, writer::WriterWithPos, KvStore};
 
   #[test]
   fn test_copy() -> Result<()> {
-    let mut buf_reader = BufReader::new(File::open(current_dir()?.join("data.log"))?);
-    let copy_file = OpenOptions::new().append(true).create(true).write(true).open(current_dir()?.join("data.copy.log"))?;
+    let dir = TempDir::new()?;
+    let mut buf_reader = BufReader::new(data_log(dir.path())?);
+    let copy_file = OpenOptions::new().append(true).create(true).open(dir.path().join("data.copy.log"))?;
     let mut copy_file_writer = WriterWithPos::new(copy_file)?;
 
     let end_seek = buf_reader.seek(std::io::SeekFrom::End(0))?;
@@ -403,4 +1950,4 @@ use super::{command::Command, writer::WriterWithPos, KvStore};
 
     Ok(())
   }
-}
\ No newline at end of file
+}
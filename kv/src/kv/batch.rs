@@ -0,0 +1,102 @@
+use std::time::Duration;
+
+use crate::error::{KvError, Result};
+
+use super::command::{request_ttl, Command};
+
+/// 一组set和remove，作为一个整体原子写入，要么全部生效，要么都不生效
+///
+/// 按添加的顺序执行，后面的操作能看到前面的结果，比如先set再remove同一个key。
+/// 其中有remove的key不存在时整批都不写入，返回[`KvError::KeyNotFound`]错误。
+///
+/// ```
+/// use kv::{engine::KvsEngine, kv::{batch::WriteBatch, memory::MemoryStore}};
+///
+/// let store = MemoryStore::new();
+/// store.set("from".to_string(), "100".to_string())?;
+/// let batch = WriteBatch::new()
+///   .remove("from".to_string())
+///   .set("to".to_string(), "100".to_string());
+/// store.write_batch(batch)?;
+/// assert_eq!(None, store.get("from".to_string())?);
+/// assert_eq!(Some("100".to_string()), store.get("to".to_string())?);
+/// # Ok::<(), kv::error::KvError>(())
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct WriteBatch {
+  ops: Vec<BatchOp>,
+}
+
+/// 批量写入中的一个操作
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BatchOp {
+  /// set，ttl之后过期，`None`永不过期
+  Set { key: String, value: String, ttl: Option<Duration> },
+  Remove { key: String },
+}
+
+impl WriteBatch {
+  pub fn new() -> WriteBatch {
+    WriteBatch::default()
+  }
+
+  pub fn set(self, key: String, value: String) -> WriteBatch {
+    self.push(BatchOp::Set { key, value, ttl: None })
+  }
+
+  /// set，ttl之后过期
+  pub fn set_with_ttl(self, key: String, value: String, ttl: Duration) -> WriteBatch {
+    self.push(BatchOp::Set { key, value, ttl: Some(ttl) })
+  }
+
+  pub fn remove(self, key: String) -> WriteBatch {
+    self.push(BatchOp::Remove { key })
+  }
+
+  fn push(mut self, op: BatchOp) -> WriteBatch {
+    self.ops.push(op);
+    self
+  }
+
+  /// 操作的数量
+  pub fn len(&self) -> usize {
+    self.ops.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.ops.is_empty()
+  }
+
+  /// 按添加顺序取出所有的操作
+  pub fn into_ops(self) -> Vec<BatchOp> {
+    self.ops
+  }
+
+  /// 转成请求中的指令
+  pub fn into_commands(self) -> Vec<Command> {
+    self.ops
+      .into_iter()
+      .map(|op| match op {
+        BatchOp::Set { key, value, ttl } => Command::Set { key, value, ttl: ttl.map(|ttl| ttl.as_millis() as u64), expires_at: None, version: None },
+        BatchOp::Remove { key } => Command::Remove { key },
+      })
+      .collect()
+  }
+}
+
+/// 请求中的指令转成批量写入，只能有set和remove
+impl TryFrom<Vec<Command>> for WriteBatch {
+  type Error = KvError;
+
+  fn try_from(cmds: Vec<Command>) -> Result<WriteBatch> {
+    let ops = cmds
+      .into_iter()
+      .map(|cmd| match cmd {
+        Command::Set { key, value, ttl, expires_at, .. } => Ok(BatchOp::Set { key, value, ttl: request_ttl(ttl, expires_at) }),
+        Command::Remove { key } => Ok(BatchOp::Remove { key }),
+        cmd => Err(KvError::Protocol(format!("批量写入中只能有set和remove: {:?}", cmd))),
+      })
+      .collect::<Result<_>>()?;
+    Ok(WriteBatch { ops })
+  }
+}
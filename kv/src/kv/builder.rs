@@ -0,0 +1,224 @@
+use std::{fmt, path::{Path, PathBuf}, str::FromStr, time::Duration};
+
+use crate::error::Result;
+
+use super::KvStore;
+
+// 默认的指令数据压缩阈值，1MB
+pub const DEFAULT_COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+
+/// 打开KvStore时的可选项
+///
+/// ```no_run
+/// use kv::kv::KvStore;
+///
+/// let store = KvStore::builder("/var/lib/kv")
+///   .compaction_threshold(1024 * 1024)
+///   .open()?;
+/// # Ok::<(), kv::error::KvError>(())
+/// ```
+pub struct KvStoreBuilder {
+  // 数据文件所在的目录
+  pub(crate) path: PathBuf,
+  // 什么时候自动压缩
+  pub(crate) compaction_policy: CompactionPolicy,
+  // 两次自动压缩之间最短的间隔
+  pub(crate) compaction_min_interval: Duration,
+  // 最新数据文件末尾数据不完整时的处理方式
+  pub(crate) recovery: RecoveryMode,
+  // 写数据后什么时候fsync
+  pub(crate) durability: Durability,
+  // 是否组提交
+  pub(crate) group_commit: bool,
+  // 定时写索引快照的间隔
+  pub(crate) checkpoint_interval: Option<Duration>,
+  // 是否只读打开
+  pub(crate) read_only: bool,
+}
+
+/// 打开时发现最新的数据文件末尾有不完整的数据（比如写到一半进程挂了），怎么处理
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RecoveryMode {
+  /// 把文件截断到最后一条完整的数据，丢弃的内容会打印出来，然后正常打开
+  #[default]
+  TruncateTail,
+  /// 直接返回错误，不修改任何文件
+  Strict,
+}
+
+/// 什么时候自动压缩合并数据文件
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompactionPolicy {
+  /// 可压缩的数据（被覆盖、删除的数据和remove指令）超过这个字节数
+  Bytes(u64),
+  /// 可压缩的数据和有效数据的比例超过这个值，例如1.0表示可压缩的数据和有效数据一样多
+  Ratio(f64),
+  /// 不自动压缩，只有调用[`KvStore::compact_now`]时才压缩
+  Manual,
+}
+
+impl Default for CompactionPolicy {
+  fn default() -> Self {
+    CompactionPolicy::Bytes(DEFAULT_COMPACTION_THRESHOLD)
+  }
+}
+
+impl CompactionPolicy {
+  /// 按当前可压缩的数据长度和有效数据长度，判断是否需要压缩
+  pub fn should_compact(&self, uncompacted: u64, live: u64) -> bool {
+    match *self {
+      CompactionPolicy::Bytes(threshold) => threshold < uncompacted,
+      CompactionPolicy::Ratio(ratio) => uncompacted > 0 && uncompacted as f64 > live as f64 * ratio,
+      CompactionPolicy::Manual => false,
+    }
+  }
+}
+
+impl FromStr for CompactionPolicy {
+  type Err = String;
+
+  /// 格式：manual、bytes:字节数、ratio:比例，例如bytes:1048576、ratio:1.5
+  fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+    let err = || format!("无法识别的压缩策略: {}，可选值为manual、bytes:字节数或者ratio:比例", s);
+    match s.split_once(':') {
+      None if s == "manual" => Ok(CompactionPolicy::Manual),
+      Some(("bytes", bytes)) => bytes.parse().map(CompactionPolicy::Bytes).map_err(|_| err()),
+      Some(("ratio", ratio)) => ratio
+        .parse::<f64>()
+        .ok()
+        .filter(|ratio| *ratio >= 0.0)
+        .map(CompactionPolicy::Ratio)
+        .ok_or_else(err),
+      _ => Err(err()),
+    }
+  }
+}
+
+impl fmt::Display for CompactionPolicy {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      CompactionPolicy::Bytes(bytes) => write!(f, "bytes:{}", bytes),
+      CompactionPolicy::Ratio(ratio) => write!(f, "ratio:{}", ratio),
+      CompactionPolicy::Manual => write!(f, "manual"),
+    }
+  }
+}
+
+/// 写数据后什么时候把数据fsync到磁盘
+///
+/// set和remove只有在满足这个策略后才会返回。
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Durability {
+  /// 每次写完都fsync，返回时数据已经在磁盘上了
+  Always,
+  /// 后台线程每隔一段时间fsync一次，断电最多丢失这段时间内的数据
+  Interval(Duration),
+  /// 只把数据交给操作系统，什么时候写到磁盘由操作系统决定
+  #[default]
+  Never,
+}
+
+impl FromStr for Durability {
+  type Err = String;
+
+  /// 格式：always、never，或者fsync的间隔毫秒数，例如100ms
+  fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+    match s {
+      "always" => Ok(Durability::Always),
+      "never" => Ok(Durability::Never),
+      _ => s
+        .trim_end_matches("ms")
+        .parse::<u64>()
+        .map(|ms| Durability::Interval(Duration::from_millis(ms.max(1))))
+        .map_err(|_| format!("无法识别的fsync策略: {}，可选值为always、never或者间隔毫秒数如100ms", s)),
+    }
+  }
+}
+
+impl fmt::Display for Durability {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Durability::Always => write!(f, "always"),
+      Durability::Interval(interval) => write!(f, "{}ms", interval.as_millis()),
+      Durability::Never => write!(f, "never"),
+    }
+  }
+}
+
+impl KvStoreBuilder {
+  pub fn new(path: impl AsRef<Path>) -> KvStoreBuilder {
+    KvStoreBuilder {
+      path: path.as_ref().to_path_buf(),
+      compaction_policy: CompactionPolicy::default(),
+      compaction_min_interval: Duration::ZERO,
+      recovery: RecoveryMode::default(),
+      durability: Durability::default(),
+      group_commit: true,
+      checkpoint_interval: None,
+      read_only: false,
+    }
+  }
+
+  /// 未被压缩的指令数据长度超过这个值时，执行压缩，等同于[`CompactionPolicy::Bytes`]
+  pub fn compaction_threshold(self, threshold: u64) -> KvStoreBuilder {
+    self.compaction_policy(CompactionPolicy::Bytes(threshold))
+  }
+
+  /// 什么时候自动压缩，默认为未被压缩的数据超过1MB
+  pub fn compaction_policy(mut self, policy: CompactionPolicy) -> KvStoreBuilder {
+    self.compaction_policy = policy;
+    self
+  }
+
+  /// 两次自动压缩之间最短的间隔，满足压缩策略但离上次压缩不到这个时间的，等到下次写入时再判断
+  pub fn compaction_min_interval(mut self, interval: Duration) -> KvStoreBuilder {
+    self.compaction_min_interval = interval;
+    self
+  }
+
+  /// 最新数据文件末尾数据不完整时的处理方式，默认为[`RecoveryMode::TruncateTail`]
+  ///
+  /// 不管哪种方式，文件中间的数据损坏或者不是最新的数据文件有问题，都会返回错误。
+  pub fn recovery(mut self, recovery: RecoveryMode) -> KvStoreBuilder {
+    self.recovery = recovery;
+    self
+  }
+
+  /// 写数据后什么时候fsync，默认为[`Durability::Never`]
+  pub fn durability(mut self, durability: Durability) -> KvStoreBuilder {
+    self.durability = durability;
+    self
+  }
+
+  /// 是否组提交，默认开启
+  ///
+  /// 多个线程同时写的时候，一个线程在落盘期间，其它线程的写入会排队，
+  /// 下一个拿到写锁的线程把排队的指令一起写入并且只落盘一次，特别适合[`Durability::Always`]。
+  pub fn group_commit(mut self, group_commit: bool) -> KvStoreBuilder {
+    self.group_commit = group_commit;
+    self
+  }
+
+  /// 每隔interval把整个索引写一次快照，默认不写
+  ///
+  /// 打开时先加载快照，只回放快照之后写入的数据；快照和数据文件对不上时回放所有数据文件。
+  pub fn checkpoint_interval(mut self, interval: Duration) -> KvStoreBuilder {
+    self.checkpoint_interval = Some(interval);
+    self
+  }
+
+  /// 是否只读打开，默认为否
+  ///
+  /// 只读打开时只建索引提供读取，不创建、写入、压缩或者删除任何文件，也不加目录锁，
+  /// 可以和一个正在写的KvStore同时打开同一个目录，但看不到打开之后别人写入的数据。
+  /// set、remove、压缩等修改数据的操作返回[`KvError::ReadOnly`](crate::error::KvError::ReadOnly)错误。
+  pub fn read_only(mut self, read_only: bool) -> KvStoreBuilder {
+    self.read_only = read_only;
+    self
+  }
+
+  /// 按当前的选项打开数据目录，目录不存在时会创建，只读打开时返回错误
+  pub fn open(self) -> Result<KvStore> {
+    KvStore::open_with(self)
+  }
+}
@@ -0,0 +1,244 @@
+use std::{collections::BTreeMap, fs::{self, File}, io::{ErrorKind, Read, Result, Write}, path::{Path, PathBuf}, sync::{mpsc::{self, RecvTimeoutError, Sender}, Arc, Mutex, RwLock}, thread::{self, JoinHandle}, time::Duration};
+
+use super::{command::CmdIdx, data_file_path, reader::Readers, record::{corruption, FieldReader}, KvWriter};
+
+/// 索引快照文件开头的标记，后面跟着2个字节的格式版本号
+pub const CHECKPOINT_MAGIC: &[u8; 6] = b"KVSCKP";
+/// 当前的索引快照文件格式版本
+pub const CHECKPOINT_VERSION: u16 = 1;
+/// 索引快照的文件名
+pub const CHECKPOINT_FILE_NAME: &str = "index.checkpoint";
+
+/// 某一时刻整个索引的快照，以及它覆盖到的数据位置
+///
+/// 打开时先加载快照，再只回放快照之后写入的数据。
+#[derive(Debug, PartialEq, Eq)]
+pub struct IndexSnapshot {
+  // 快照覆盖到的数据文件，编号比它小的数据文件已经全部包含在快照中
+  pub file: u32,
+  // 快照覆盖到的数据文件中的位置
+  pub pos: u64,
+  // 快照时可以压缩的数据长度
+  pub uncompacted: u64,
+  // 快照时已经分配出去的最大的数据版本号
+  pub max_version: u64,
+  // 编号比file小的数据文件和它们的长度，用来检查快照之后数据文件有没有变过
+  pub files: Vec<(u32, u64)>,
+  pub index: BTreeMap<String, CmdIdx>,
+}
+
+impl IndexSnapshot {
+  /// 编码后的字节
+  ///
+  /// 格式：标记 | 版本号 | 数据文件 | 位置 | 可压缩长度 | 最大的数据版本号 | 文件数 | (文件 | 长度)...
+  /// | 条数 | (key长度 | key | 文件 | 位置 | 长度 | 过期时间 | 数据版本号)... | crc32，数字都是小端序。
+  /// 过期时间是unix时间戳毫秒，0表示永不过期。
+  pub fn encode(&self) -> Vec<u8> {
+    let mut buf = CHECKPOINT_MAGIC.to_vec();
+    buf.extend_from_slice(&CHECKPOINT_VERSION.to_le_bytes());
+    buf.extend_from_slice(&self.file.to_le_bytes());
+    buf.extend_from_slice(&self.pos.to_le_bytes());
+    buf.extend_from_slice(&self.uncompacted.to_le_bytes());
+    buf.extend_from_slice(&self.max_version.to_le_bytes());
+    buf.extend_from_slice(&(self.files.len() as u32).to_le_bytes());
+    for (file, len) in &self.files {
+      buf.extend_from_slice(&file.to_le_bytes());
+      buf.extend_from_slice(&len.to_le_bytes());
+    }
+    buf.extend_from_slice(&(self.index.len() as u64).to_le_bytes());
+    for (key, cmd_idx) in &self.index {
+      buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+      buf.extend_from_slice(key.as_bytes());
+      buf.extend_from_slice(&cmd_idx.file.to_le_bytes());
+      buf.extend_from_slice(&cmd_idx.pos.to_le_bytes());
+      buf.extend_from_slice(&cmd_idx.len.to_le_bytes());
+      buf.extend_from_slice(&cmd_idx.expires_at.unwrap_or(0).to_le_bytes());
+      buf.extend_from_slice(&cmd_idx.version.to_le_bytes());
+    }
+    buf.extend_from_slice(&crc32fast::hash(&buf).to_le_bytes());
+    buf
+  }
+
+  /// 解码，内容损坏返回`ErrorKind::InvalidData`
+  pub fn decode(buf: &[u8]) -> Result<IndexSnapshot> {
+    if buf.len() < CHECKPOINT_MAGIC.len() + 2 + 4 || !buf.starts_with(CHECKPOINT_MAGIC) {
+      return Err(corruption("不是索引快照文件".to_string()));
+    }
+    let (body, crc) = buf.split_at(buf.len() - 4);
+    if crc32fast::hash(body).to_le_bytes() != crc {
+      return Err(corruption("索引快照crc校验失败".to_string()));
+    }
+    let mut reader = FieldReader::new(&body[CHECKPOINT_MAGIC.len()..]);
+    let version = reader.u16()?;
+    if version != CHECKPOINT_VERSION {
+      return Err(corruption(format!("不支持的索引快照版本{}", version)));
+    }
+    let file = reader.u32()?;
+    let pos = reader.u64()?;
+    let uncompacted = reader.u64()?;
+    let mut max_version = reader.u64()?;
+    let files = (0..reader.u32()?)
+      .map(|_| Ok((reader.u32()?, reader.u64()?)))
+      .collect::<Result<Vec<_>>>()?;
+    let mut index = BTreeMap::new();
+    for _ in 0..reader.u64()? {
+      let key = reader.string()?;
+      let cmd_idx = CmdIdx {
+        file: reader.u32()?,
+        pos: reader.u64()?,
+        len: reader.u64()?,
+        expires_at: Some(reader.u64()?).filter(|&at| at != 0),
+        version: reader.u64()?,
+      };
+      max_version = max_version.max(cmd_idx.version);
+      index.insert(key, cmd_idx);
+    }
+    Ok(IndexSnapshot { file, pos, uncompacted, max_version, files, index })
+  }
+
+  /// 读取数据目录中的索引快照，并检查它和现在的数据文件是否对得上，没有快照时返回`None`
+  ///
+  /// 快照之后数据文件被压缩、截断或者修改过，快照就不能用了，返回`ErrorKind::InvalidData`错误。
+  pub fn load(dir: &Path, file_names: &[u32]) -> Result<Option<IndexSnapshot>> {
+    let mut buf = Vec::new();
+    match File::open(dir.join(CHECKPOINT_FILE_NAME)) {
+      Ok(mut file) => file.read_to_end(&mut buf)?,
+      Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+      Err(e) => return Err(e),
+    };
+    let snapshot = IndexSnapshot::decode(&buf)?;
+    // 编号比快照文件小的数据文件要和快照时一模一样
+    let older = file_names.iter().cloned().filter(|&name| name < snapshot.file).collect::<Vec<_>>();
+    if older != snapshot.files.iter().map(|(name, _)| *name).collect::<Vec<_>>() {
+      return Err(corruption("快照之后数据文件有增减".to_string()));
+    }
+    for (name, len) in &snapshot.files {
+      let actual = fs::metadata(data_file_path(dir, *name))?.len();
+      if actual != *len {
+        return Err(corruption(format!("{}.log: 快照时长度为{}，现在为{}", name, len, actual)));
+      }
+    }
+    // 快照覆盖到的数据文件只能在后面追加
+    let len = match fs::metadata(data_file_path(dir, snapshot.file)) {
+      Ok(metadata) => metadata.len(),
+      Err(e) if e.kind() == ErrorKind::NotFound => 0,
+      Err(e) => return Err(e),
+    };
+    if len < snapshot.pos {
+      return Err(corruption(format!("{}.log: 快照覆盖到位置{}，文件只有{}字节", snapshot.file, snapshot.pos, len)));
+    }
+    Ok(Some(snapshot))
+  }
+
+  /// 写入数据目录，先写临时文件，落盘后再改名
+  pub fn save(&self, dir: &Path) -> Result<()> {
+    let path = dir.join(CHECKPOINT_FILE_NAME);
+    let tmp_path = path.with_extension("checkpoint.tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&self.encode())?;
+    file.sync_all()?;
+    fs::rename(tmp_path, path)
+  }
+}
+
+/// 写索引快照时用到的数据，和KvStore共享
+#[derive(Clone)]
+pub(crate) struct Checkpoint {
+  pub data_path: Arc<PathBuf>,
+  pub index: Arc<RwLock<BTreeMap<String, CmdIdx>>>,
+  pub readers: Readers,
+  pub writer: Arc<Mutex<KvWriter>>,
+  // 同一时间只能有一个快照在写
+  pub running: Arc<Mutex<()>>,
+}
+
+impl Checkpoint {
+  /// 写一次索引快照
+  ///
+  /// 只有复制索引的时候拿写锁，索引只在拿着写锁时修改，复制出来的索引和写入位置是一致的。
+  pub fn run(&self) -> crate::error::Result<()> {
+    let _running = self.running.lock().unwrap();
+    let snapshot = {
+      let mut writer = self.writer.lock().unwrap();
+      // 快照覆盖到的数据至少要交给操作系统
+      let data_file = writer.data_file()?;
+      data_file.flush()?;
+      let pos = data_file.pos;
+      let files = self.readers
+        .file_names()
+        .into_iter()
+        .filter(|&name| name < writer.cur_data_file_name)
+        .filter_map(|name| match fs::metadata(data_file_path(&self.data_path, name)) {
+          Ok(metadata) => Some(Ok((name, metadata.len()))),
+          // 正在压缩的文件还是临时文件名，索引也还没有指向它，快照中不用记
+          Err(e) if e.kind() == ErrorKind::NotFound => None,
+          Err(e) => Some(Err(e)),
+        })
+        .collect::<Result<Vec<_>>>()?;
+      IndexSnapshot {
+        file: writer.cur_data_file_name,
+        pos,
+        uncompacted: writer.uncompacted,
+        max_version: writer.version,
+        files,
+        index: self.index.read().unwrap().clone(),
+      }
+    };
+    Ok(snapshot.save(&self.data_path)?)
+  }
+}
+
+/// 定时写索引快照的后台线程
+pub(crate) struct Checkpointer {
+  sender: Option<Sender<()>>,
+  handle: Option<JoinHandle<()>>,
+}
+
+impl Checkpointer {
+  pub fn spawn(checkpoint: Checkpoint, interval: Duration) -> Result<Checkpointer> {
+    let (sender, receiver) = mpsc::channel::<()>();
+    let handle = thread::Builder::new()
+      .name("kv-checkpointer".to_string())
+      .spawn(move || {
+        // 每隔interval写一次，sender被drop之后退出
+        while let Err(RecvTimeoutError::Timeout) = receiver.recv_timeout(interval) {
+          if let Err(e) = checkpoint.run() {
+            println!("索引快照写入失败！{}", e);
+          }
+        }
+      })?;
+    Ok(Checkpointer { sender: Some(sender), handle: Some(handle) })
+  }
+}
+
+impl Drop for Checkpointer {
+  fn drop(&mut self) {
+    drop(self.sender.take());
+    if let Some(handle) = self.handle.take() {
+      let _ = handle.join();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{collections::BTreeMap, io::{ErrorKind, Result}};
+
+  use crate::kv::command::CmdIdx;
+
+  use super::IndexSnapshot;
+
+  #[test]
+  fn test_encode_decode_snapshot() -> Result<()> {
+    let mut index = BTreeMap::new();
+    index.insert("a".to_string(), CmdIdx { file: 1, pos: 8, len: 40, expires_at: None, version: 1 });
+    index.insert("b".to_string(), CmdIdx { file: 3, pos: 8, len: 42, expires_at: Some(1_700_000_000_000), version: 2 });
+    let snapshot = IndexSnapshot { file: 3, pos: 50, uncompacted: 40, max_version: 4, files: vec![(1, 88)], index };
+    let mut buf = snapshot.encode();
+    assert_eq!(snapshot, IndexSnapshot::decode(&buf)?);
+
+    buf[20] ^= 0xff;
+    assert_eq!(ErrorKind::InvalidData, IndexSnapshot::decode(&buf).unwrap_err().kind());
+    Ok(())
+  }
+}
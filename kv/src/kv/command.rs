@@ -1,10 +1,14 @@
-use std::ops::Range;
+use std::{ops::Range, time::Duration};
 
 use clap::{Parser, Subcommand};
 use serde::{Deserialize, Serialize};
 
+use super::record::now_millis;
+
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
+#[command(after_help = "退出码：0 成功，1 连不上服务端或者连接断开，2 命令行参数错误，\
+3 key不存在（get、get-versioned没有找到key，或者remove的key不存在），4 请求有问题（格式不对、服务端只读），5 服务端处理失败，6 条件写的条件不满足")]
 pub struct Cli {
   #[arg(short, long, value_name = "IP:PORT")]
   pub port: Option<String>,
@@ -21,17 +25,125 @@ pub enum Command {
     key: String,
     /// value
     value: String,
+    /// 多长时间后过期，例如500ms、30s、5m、1h、1d，不填永不过期
+    #[arg(long, value_name = "DURATION", value_parser = parse_ttl)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ttl: Option<u64>,
+    /// 过期的时间点，unix时间戳毫秒。写入数据文件时由ttl换算出来，过期时间就跟着记录保存下来了
+    #[arg(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+    /// 版本号，写入数据文件时由KvStore分配，请求中的会被忽略
+    #[arg(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    version: Option<u64>,
   },
   Get {
     /// key
     key: String,
   },
+  /// 取值和它的版本号，版本号用于set-if-version
+  GetVersioned {
+    /// key
+    key: String,
+  },
+  /// key不存在时才set，成功后输出新的版本号
+  SetIfAbsent {
+    /// key
+    key: String,
+    /// value
+    value: String,
+  },
+  /// key当前的值等于EXPECTED时才set，成功后输出新的版本号
+  SetIfEquals {
+    /// key
+    key: String,
+    /// value
+    value: String,
+    /// 期望的当前值
+    #[arg(long)]
+    expected: String,
+  },
+  /// key当前的版本号等于VERSION时才set，成功后输出新的版本号
+  SetIfVersion {
+    /// key
+    key: String,
+    /// value
+    value: String,
+    /// 期望的当前版本号
+    #[arg(long)]
+    version: u64,
+  },
   Remove {
     /// key
     key: String,
+  },
+  /// 按key的顺序列出[START, END)范围内的键值对
+  Scan {
+    /// 开始的key（包含），不填从第一个key开始
+    start: Option<String>,
+    /// 结束的key（不包含），不填到最后一个key
+    end: Option<String>,
+    /// 最多返回多少个。请求中是一页的数量，服务端会限制一页的最大数量
+    #[arg(short, long)]
+    limit: Option<usize>,
+    /// 上一页返回的游标，从这个key之后接着取，命令行会自动翻页
+    #[arg(skip)]
+    cursor: Option<String>,
+  },
+  /// 按顺序列出以PREFIX开头的所有key，不读value
+  Keys {
+    /// key的前缀，不填列出所有的key
+    #[arg(default_value = "")]
+    prefix: String,
+  },
+  /// 以PREFIX开头的key的数量
+  Count {
+    /// key的前缀，不填是所有key的数量
+    #[arg(default_value = "")]
+    prefix: String,
+  },
+  /// 立即压缩合并数据文件
+  Compact,
+  /// 一组原子写入的set和remove，要么全部生效，要么都不生效。只在协议中使用，命令行没有这个命令
+  #[command(skip)]
+  Batch {
+    ops: Vec<Command>,
+  },
+}
+
+impl Command {
+  /// 不过期的set
+  pub fn set(key: String, value: String) -> Command {
+    Command::Set { key, value, ttl: None, expires_at: None, version: None }
   }
 }
 
+/// 请求中set的过期时间：可以是多长时间后过期，也可以是过期的时间点，都没有时永不过期
+pub(crate) fn request_ttl(ttl: Option<u64>, expires_at: Option<u64>) -> Option<Duration> {
+  ttl
+    .map(Duration::from_millis)
+    .or_else(|| expires_at.map(|expires_at| Duration::from_millis(expires_at.saturating_sub(now_millis()))))
+}
+
+/// 解析ttl，格式是数字加单位：ms、s、m、h、d，例如30s，返回毫秒数
+pub fn parse_ttl(s: &str) -> Result<u64, String> {
+  let err = || format!("无法识别的过期时间: {}，格式为数字加单位ms、s、m、h、d，例如30s", s);
+  let split = s.find(|c: char| !c.is_ascii_digit()).ok_or_else(err)?;
+  let (num, unit) = s.split_at(split);
+  let num: u64 = num.parse().map_err(|_| err())?;
+  let millis = match unit {
+    "ms" => 1,
+    "s" => 1000,
+    "m" => 60 * 1000,
+    "h" => 60 * 60 * 1000,
+    "d" => 24 * 60 * 60 * 1000,
+    _ => return Err(err()),
+  };
+  num.checked_mul(millis).ok_or_else(err)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct CmdIdx {
   // 索引所在的数据文件
   pub file: u32,
@@ -39,12 +151,38 @@ pub struct CmdIdx {
   pub pos: u64,
   // 数据长度
   pub len: u64,
+  // set的数据过期的时间点，unix时间戳毫秒，None表示永不过期
+  pub expires_at: Option<u64>,
+  // set的数据的版本号，没有版本号的旧数据是0
+  pub version: u64,
+}
+
+impl CmdIdx {
+  /// 在now（unix时间戳毫秒）的时候是否已经过期
+  pub fn is_expired(&self, now: u64) -> bool {
+    matches!(self.expires_at, Some(expires_at) if expires_at <= now)
+  }
 }
 
 type Idx =(u32, Range<u64>); 
 
 impl From<Idx> for CmdIdx {
     fn from((file, range): Idx) -> Self {
-      CmdIdx {file, pos: range.start, len: range.end - range.start} 
+      CmdIdx {file, pos: range.start, len: range.end - range.start, expires_at: None, version: 0} 
     }
-} 
\ No newline at end of file
+} 
+#[cfg(test)]
+mod tests {
+  use super::parse_ttl;
+
+  #[test]
+  fn test_parse_ttl() {
+    assert_eq!(Ok(500), parse_ttl("500ms"));
+    assert_eq!(Ok(30_000), parse_ttl("30s"));
+    assert_eq!(Ok(5 * 60_000), parse_ttl("5m"));
+    assert_eq!(Ok(2 * 86_400_000), parse_ttl("2d"));
+    assert!(parse_ttl("30").is_err());
+    assert!(parse_ttl("s").is_err());
+    assert!(parse_ttl("1.5h").is_err());
+  }
+}
@@ -0,0 +1,253 @@
+use std::{collections::BTreeMap, ffi::OsStr, fs::{self, File}, io::{self, ErrorKind, Write}, mem, path::{Path, PathBuf}, sync::{mpsc::{self, Sender}, Arc, Mutex, RwLock}, thread::{self, JoinHandle}, time::Instant};
+
+use crate::error::{KvError, Result};
+
+use super::{builder::Durability, command::CmdIdx, data_file_path, hint::{hint_file_path, write_hint}, new_data_file, open_data_file, record::now_millis, reader::Readers, writer::WriterWithPos, KvWriter};
+
+// 快照中的一个key：快照时的索引，复制到压缩文件后的索引，过期了没有复制的是None
+type Moved = (String, CmdIdx, Option<CmdIdx>);
+
+/// 压缩过程中写入的临时文件，全部写完、落盘之后才改名成N.log
+///
+/// 压缩到一半崩溃时留下的只有临时文件，打开时删掉就行，不会把写了一半的压缩文件当成数据文件。
+pub(crate) fn compaction_file_path(dir: &Path, file_name: u32) -> PathBuf {
+  dir.join(format!("{}.log.compact", file_name))
+}
+
+/// 删除上次压缩到一半留下的临时文件，以及还没有对应数据文件的索引文件
+pub(crate) fn remove_leftovers(dir: &Path) -> io::Result<()> {
+  for entry in fs::read_dir(dir)? {
+    let path = entry?.path();
+    let file_name = path
+      .file_name()
+      .and_then(OsStr::to_str)
+      .and_then(|name| name.strip_suffix(".log.compact"))
+      .and_then(|name| name.parse::<u32>().ok());
+    let Some(file_name) = file_name else {
+      continue;
+    };
+    println!("{} 是上次没有完成的压缩留下的，删除", path.display());
+    fs::remove_file(&path)?;
+    if !data_file_path(dir, file_name).exists() {
+      match fs::remove_file(hint_file_path(dir, file_name)) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+        _ => (),
+      }
+    }
+  }
+  Ok(())
+}
+
+/// 压缩合并数据文件时用到的数据，和KvStore共享
+#[derive(Clone)]
+pub(crate) struct Compaction {
+  pub data_path: Arc<PathBuf>,
+  pub index: Arc<RwLock<BTreeMap<String, CmdIdx>>>,
+  pub readers: Readers,
+  pub writer: Arc<Mutex<KvWriter>>,
+  // 同一时间只能有一个压缩在执行
+  pub running: Arc<Mutex<()>>,
+}
+
+impl Compaction {
+  /// 压缩合并数据文件
+  ///
+  /// 只有切换数据文件和最后更新索引的时候会拿写锁，复制数据的过程中新的写入照常进行，
+  /// 新的数据写入新的数据文件。
+  pub fn run(&self) -> Result<()> {
+    let _running = self.running.lock().unwrap();
+    let result = self.compact();
+    // 不管成功失败，都允许再次触发压缩
+    let mut writer = self.writer.lock().unwrap();
+    writer.compacting = false;
+    writer.last_compaction = Some(Instant::now());
+    result
+  }
+
+  fn compact(&self) -> Result<()> {
+    // 第一步：拿写锁，切换到新的数据文件，并拿一份索引的快照
+    // 快照中的数据都在压缩文件编号之前的文件中，之后新来的数据都写到新的数据文件中
+    let (compaction_file_name, compaction_writer, snapshot, durability, max_version, uncompacted) = {
+      let mut writer = self.writer.lock().unwrap();
+      // 旧的数据文件不会再写了，先落盘，只读打开时这里返回错误
+      writer.data_file()?;
+      writer.sync()?;
+      // 压缩后要写入的文件
+      let compaction_file_name = writer.cur_data_file_name + 1;
+      let compaction_writer = open_data_file(&compaction_file_path(&self.data_path, compaction_file_name), compaction_file_name, &self.readers)?;
+      // 新来的数据写入的数据文件，区别于合并压缩过的数据文件
+      let cur_data_file_name = compaction_file_name + 1;
+      writer.writer = Some(new_data_file(&self.data_path, cur_data_file_name, &self.readers)?);
+      // 重新设置当前的数据文件
+      writer.cur_data_file_name = cur_data_file_name;
+      // 快照之后的可压缩长度重新开始计算，压缩失败时再加回来
+      let uncompacted = mem::take(&mut writer.uncompacted);
+      let snapshot = self.index.read().unwrap().clone();
+      (compaction_file_name, compaction_writer, snapshot, writer.durability, writer.version, uncompacted)
+    };
+
+    // 第二步：不拿锁，把快照中的数据复制到压缩文件中
+    let moved = match self.copy(compaction_file_name, compaction_writer, snapshot, durability, max_version) {
+      Ok(moved) => moved,
+      Err(e) => {
+        // 旧的数据文件都还在，索引也没有动过，删掉写了一半的压缩文件就行，之前的可压缩长度也还有效
+        self.readers.remove(compaction_file_name);
+        for path in [compaction_file_path(&self.data_path, compaction_file_name), hint_file_path(&self.data_path, compaction_file_name)] {
+          if let Err(e) = fs::remove_file(&path) {
+            if e.kind() != ErrorKind::NotFound {
+              println!("{} 删除失败！{}", path.display(), e);
+            }
+          }
+        }
+        self.writer.lock().unwrap().uncompacted += uncompacted;
+        return Err(e);
+      },
+    };
+
+    // 第三步：拿写锁，一次性更新索引
+    {
+      let mut writer = self.writer.lock().unwrap();
+      let mut index = self.index.write().unwrap();
+      for (key, old_idx, new_idx) in moved {
+        let unchanged = index.get(&key) == Some(&old_idx);
+        match new_idx {
+          // 快照之后没有变过，指向压缩文件中的数据
+          // 旧格式的数据转换后长度会变，有效数据长度也跟着变
+          Some(new_idx) if unchanged => {
+            index.insert(key, new_idx);
+            writer.live = writer.live - old_idx.len + new_idx.len;
+          },
+          // 快照之后被覆盖或者删除了，压缩文件中的这条数据就成了可压缩的数据
+          // 之前累加的旧数据长度所在的文件马上就要删掉了，换成压缩文件中的长度
+          Some(new_idx) => writer.uncompacted = writer.uncompacted.saturating_sub(old_idx.len) + new_idx.len,
+          // 过期的数据没有复制，旧文件删掉后就没有了，从索引中去掉
+          None if unchanged => {
+            index.remove(&key);
+            writer.live -= old_idx.len;
+          },
+          None => writer.uncompacted = writer.uncompacted.saturating_sub(old_idx.len),
+        }
+      }
+    }
+
+    // 第四步：索引已经更新完了，新的读操作都会去读压缩后的文件，清除旧的数据文件
+    let old_file_names = self.readers
+      .file_names()
+      .into_iter()
+      // 过滤出小于压缩合并文件的文件名，这已经是旧文件了。
+      .filter(|&res| res < compaction_file_name)
+      .collect::<Vec<u32>>();
+    for file_name in old_file_names {
+      // 删除旧文件的reader，还在读这个文件的线程不受影响
+      self.readers.remove(file_name);
+      // 删除旧文件，以及它的索引文件
+      fs::remove_file(data_file_path(&self.data_path, file_name))?;
+      match fs::remove_file(hint_file_path(&self.data_path, file_name)) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+        _ => (),
+      }
+    }
+
+    Ok(())
+  }
+
+  // 把快照中的数据复制到压缩文件中，已经过期的数据不用复制，返回每个key复制前后的索引
+  fn copy(&self,
+    compaction_file_name: u32,
+    mut compaction_writer: WriterWithPos<File>,
+    snapshot: BTreeMap<String, CmdIdx>,
+    durability: Durability,
+    max_version: u64) -> Result<Vec<Moved>> {
+    let now = now_millis();
+    let mut moved = Vec::with_capacity(snapshot.len());
+    for (key, cmd_idx) in snapshot {
+      if cmd_idx.is_expired(now) {
+        moved.push((key, cmd_idx, None));
+        continue;
+      }
+      // 取出当前索引的reader，压缩时不会有别的线程删除数据文件，找不到说明索引和数据文件对不上
+      let reader = self.readers
+        .get(cmd_idx.file)
+        .ok_or_else(|| KvError::Corruption(format!("没有找到数据文件: {}.log", cmd_idx.file)))?;
+      // 将索引对应的数据copy到压缩合并后的新数据文件中
+      // 旧格式的数据在这里会转成新的记录格式
+      let record = reader.read_record(cmd_idx.pos, cmd_idx.len)?;
+      let start = compaction_writer.pos;
+      compaction_writer.write_all(&record.encode())?;
+      let end = compaction_writer.pos;
+      let new_idx = CmdIdx { file: compaction_file_name, pos: start, len: end - start, ..cmd_idx };
+      moved.push((key, cmd_idx, Some(new_idx)));
+    }
+    // 删除旧文件之前，压缩文件要先落盘，否则断电后新旧数据都可能丢失
+    if durability == Durability::Never {
+      compaction_writer.flush()?;
+    } else {
+      compaction_writer.sync()?;
+    }
+    // 压缩文件旁边写一个索引文件，下次打开时不用回放压缩文件
+    // 索引文件中记下已经分配出去的最大版本号，删除的key的版本号重新打开后也不会再分配
+    // 删除的key的版本号只有这里有，写失败了这次压缩也要失败，不然重新打开后版本号会重复分配
+    let entries: Vec<_> = moved.iter().filter_map(|(key, _, new_idx)| Some((key.as_str(), new_idx.as_ref()?))).collect();
+    write_hint(&self.data_path, compaction_file_name, compaction_writer.pos, max_version, entries.into_iter())?;
+    // 都写好了再改成数据文件的名字，打开时看到的压缩文件都是完整的
+    // reader打开的还是同一个文件，改名不影响读
+    fs::rename(compaction_file_path(&self.data_path, compaction_file_name), data_file_path(&self.data_path, compaction_file_name))?;
+    sync_dir(&self.data_path, durability)?;
+    Ok(moved)
+  }
+}
+
+/// 后台压缩线程
+///
+/// 最后一个KvStore drop的时候，等线程把手上的压缩做完再退出，避免和下一次打开同一个目录冲突。
+pub(crate) struct Compactor {
+  sender: Option<Sender<()>>,
+  handle: Option<JoinHandle<()>>,
+}
+
+impl Compactor {
+  pub fn spawn(compaction: Compaction) -> io::Result<Compactor> {
+    let (sender, receiver) = mpsc::channel();
+    let handle = thread::Builder::new()
+      .name("kv-compactor".to_string())
+      .spawn(move || {
+        // sender被drop之后退出
+        while receiver.recv().is_ok() {
+          if let Err(e) = compaction.run() {
+            println!("数据文件压缩失败！{}", e);
+          }
+        }
+      })?;
+    Ok(Compactor { sender: Some(sender), handle: Some(handle) })
+  }
+
+  /// 通知后台线程执行一次压缩
+  pub fn trigger(&self) {
+    if let Some(sender) = &self.sender {
+      let _ = sender.send(());
+    }
+  }
+}
+
+impl Drop for Compactor {
+  fn drop(&mut self) {
+    drop(self.sender.take());
+    if let Some(handle) = self.handle.take() {
+      let _ = handle.join();
+    }
+  }
+}
+
+// 改名之后目录也要落盘，否则断电后改名可能丢失
+#[cfg(unix)]
+fn sync_dir(dir: &Path, durability: Durability) -> io::Result<()> {
+  if durability == Durability::Never {
+    return Ok(());
+  }
+  File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path, _durability: Durability) -> io::Result<()> {
+  Ok(())
+}
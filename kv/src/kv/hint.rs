@@ -0,0 +1,143 @@
+use std::{fs::{self, File}, io::{ErrorKind, Read, Result, Write}, path::{Path, PathBuf}};
+
+use super::{command::CmdIdx, record::{corruption, FieldReader}};
+
+/// 索引文件开头的标记，后面跟着2个字节的格式版本号
+///
+/// 压缩生成的数据文件旁边会写一个同名的.hint索引文件，只有key和数据的位置，没有value，
+/// 打开时直接用它建索引，不用回放整个数据文件。
+pub const HINT_MAGIC: &[u8; 6] = b"KVSHNT";
+/// 当前的索引文件格式版本
+pub const HINT_VERSION: u16 = 1;
+
+pub fn hint_file_path(dir: &Path, file_name: u32) -> PathBuf {
+  dir.join(format!("{}.hint", file_name))
+}
+
+/// 写入数据文件对应的索引文件，先写临时文件，落盘后再改名，不会留下写了一半的索引文件
+///
+/// 格式：标记 | 版本号 | 数据文件长度 | 最大的数据版本号 | 条数 | (key长度 | key | 位置 | 长度 | 过期时间 | 数据版本号)...
+/// | crc32，数字都是小端序。过期时间是unix时间戳毫秒，0表示永不过期。crc32校验的是前面所有的内容。
+///
+/// 最大的数据版本号是压缩时已经分配出去的版本号，包括已经删除的key的，重新打开后从它之后继续分配。
+pub fn write_hint<'a>(dir: &Path,
+  file_name: u32,
+  data_len: u64,
+  max_version: u64,
+  entries: impl ExactSizeIterator<Item = (&'a str, &'a CmdIdx)>) -> Result<()> {
+  let mut buf = HINT_MAGIC.to_vec();
+  buf.extend_from_slice(&HINT_VERSION.to_le_bytes());
+  buf.extend_from_slice(&data_len.to_le_bytes());
+  buf.extend_from_slice(&max_version.to_le_bytes());
+  buf.extend_from_slice(&(entries.len() as u64).to_le_bytes());
+  for (key, cmd_idx) in entries {
+    buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    buf.extend_from_slice(key.as_bytes());
+    buf.extend_from_slice(&cmd_idx.pos.to_le_bytes());
+    buf.extend_from_slice(&cmd_idx.len.to_le_bytes());
+    buf.extend_from_slice(&cmd_idx.expires_at.unwrap_or(0).to_le_bytes());
+    buf.extend_from_slice(&cmd_idx.version.to_le_bytes());
+  }
+  buf.extend_from_slice(&crc32fast::hash(&buf).to_le_bytes());
+
+  let path = hint_file_path(dir, file_name);
+  let tmp_path = path.with_extension("hint.tmp");
+  let mut file = File::create(&tmp_path)?;
+  file.write_all(&buf)?;
+  file.sync_all()?;
+  fs::rename(tmp_path, path)
+}
+
+/// 索引文件中的内容
+#[derive(Debug, PartialEq, Eq)]
+pub struct Hint {
+  // 写索引文件时最大的数据版本号
+  pub max_version: u64,
+  pub entries: Vec<(String, CmdIdx)>,
+}
+
+/// 读取数据文件对应的索引文件，没有索引文件时返回`None`
+///
+/// 索引文件损坏，或者记录的数据文件长度和实际的不一致，返回`ErrorKind::InvalidData`错误。
+pub fn read_hint(dir: &Path, file_name: u32, data_len: u64) -> Result<Option<Hint>> {
+  let mut buf = Vec::new();
+  match File::open(hint_file_path(dir, file_name)) {
+    Ok(mut file) => file.read_to_end(&mut buf)?,
+    Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+    Err(e) => return Err(e),
+  };
+  let header_len = HINT_MAGIC.len() + 2 + 8 + 8;
+  if buf.len() < header_len + 4 || !buf.starts_with(HINT_MAGIC) {
+    return Err(corruption(format!("{}.hint: 不是索引文件", file_name)));
+  }
+  let (body, crc) = buf.split_at(buf.len() - 4);
+  if crc32fast::hash(body).to_le_bytes() != crc {
+    return Err(corruption(format!("{}.hint: crc校验失败", file_name)));
+  }
+  let mut reader = FieldReader::new(&body[HINT_MAGIC.len()..]);
+  let version = reader.u16()?;
+  if version != HINT_VERSION {
+    return Err(corruption(format!("{}.hint: 不支持的索引文件版本{}", file_name, version)));
+  }
+  let hint_data_len = reader.u64()?;
+  if hint_data_len != data_len {
+    return Err(corruption(format!("{}.hint: 记录的数据文件长度为{}，实际为{}", file_name, hint_data_len, data_len)));
+  }
+  let mut max_version = reader.u64()?;
+  let count = reader.u64()?;
+  let mut entries = Vec::new();
+  for _ in 0..count {
+    let key = reader.string().map_err(|e| corruption(format!("{}.hint: {}", file_name, e)))?;
+    let pos = reader.u64()?;
+    let len = reader.u64()?;
+    let expires_at = Some(reader.u64()?).filter(|&at| at != 0);
+    let data_version = reader.u64()?;
+    max_version = max_version.max(data_version);
+    if pos + len > data_len {
+      return Err(corruption(format!("{}.hint: 位置{}超出了数据文件", file_name, pos)));
+    }
+    entries.push((key, CmdIdx { file: file_name, pos, len, expires_at, version: data_version }));
+  }
+  Ok(Some(Hint { max_version, entries }))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{fs, io::{ErrorKind, Result}};
+
+  use tempfile::TempDir;
+
+  use crate::kv::command::CmdIdx;
+
+  use super::{hint_file_path, read_hint, write_hint, Hint};
+
+  #[test]
+  fn test_write_read_hint() -> Result<()> {
+    let dir = TempDir::new()?;
+    let entries = vec![
+      ("a".to_string(), CmdIdx { file: 3, pos: 8, len: 40, expires_at: None, version: 1 }),
+      ("b".to_string(), CmdIdx { file: 3, pos: 48, len: 42, expires_at: Some(1_700_000_000_000), version: 3 }),
+    ];
+    write_hint(dir.path(), 3, 90, 5, entries.iter().map(|(key, cmd_idx)| (key.as_str(), cmd_idx)))?;
+    assert_eq!(Some(Hint { max_version: 5, entries }), read_hint(dir.path(), 3, 90)?);
+    // 没有索引文件
+    assert_eq!(None, read_hint(dir.path(), 4, 90)?);
+    // 数据文件长度对不上，比如数据文件被改过
+    assert_eq!(ErrorKind::InvalidData, read_hint(dir.path(), 3, 100).unwrap_err().kind());
+    Ok(())
+  }
+
+  #[test]
+  fn test_corrupted_hint() -> Result<()> {
+    let dir = TempDir::new()?;
+    let cmd_idx = CmdIdx { file: 1, pos: 8, len: 40, expires_at: None, version: 1 };
+    write_hint(dir.path(), 1, 48, 1, [("key", &cmd_idx)].into_iter())?;
+    let path = hint_file_path(dir.path(), 1);
+    let mut buf = fs::read(&path)?;
+    let last = buf.len() - 10;
+    buf[last] ^= 0xff;
+    fs::write(&path, buf)?;
+    assert_eq!(ErrorKind::InvalidData, read_hint(dir.path(), 1, 48).unwrap_err().kind());
+    Ok(())
+  }
+}
@@ -0,0 +1,75 @@
+use std::{fs::{File, OpenOptions, TryLockError}, io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Write}, path::Path, process};
+
+/// 锁文件的文件名
+pub const LOCK_FILE_NAME: &str = "LOCK";
+
+/// 数据目录的排它锁，防止两个进程同时写同一个数据目录
+///
+/// 锁文件中写着持有锁的进程号，drop的时候释放锁，锁文件本身留着。
+/// 锁是建议性的，只对同样会加锁的程序有效。
+#[derive(Debug)]
+pub struct DirLock {
+  file: File,
+}
+
+impl DirLock {
+  /// 对数据目录加锁，已经被别的进程锁住时返回`ErrorKind::ResourceBusy`错误，错误信息中有对方的进程号
+  pub fn acquire(dir: &Path) -> Result<DirLock> {
+    let mut file = OpenOptions::new()
+      .create(true)
+      .truncate(false)
+      .read(true)
+      .write(true)
+      .open(dir.join(LOCK_FILE_NAME))?;
+    match file.try_lock() {
+      Ok(()) => (),
+      Err(TryLockError::WouldBlock) => {
+        let mut pid = String::new();
+        file.read_to_string(&mut pid)?;
+        let pid = if pid.trim().is_empty() { "未知" } else { pid.trim() };
+        return Err(Error::new(
+          ErrorKind::ResourceBusy,
+          format!("数据目录{}已经被进程{}打开", dir.display(), pid),
+        ));
+      },
+      Err(TryLockError::Error(e)) => return Err(e),
+    }
+    // 拿到锁之后再写入自己的进程号
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    write!(file, "{}", process::id())?;
+    file.sync_data()?;
+    Ok(DirLock { file })
+  }
+}
+
+impl Drop for DirLock {
+  fn drop(&mut self) {
+    let _ = self.file.unlock();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{fs, io::{ErrorKind, Result}, process};
+
+  use tempfile::TempDir;
+
+  use super::{DirLock, LOCK_FILE_NAME};
+
+  #[test]
+  fn test_dir_lock() -> Result<()> {
+    let dir = TempDir::new()?;
+    let lock = DirLock::acquire(dir.path())?;
+    assert_eq!(process::id().to_string(), fs::read_to_string(dir.path().join(LOCK_FILE_NAME))?);
+
+    let err = DirLock::acquire(dir.path()).unwrap_err();
+    assert_eq!(ErrorKind::ResourceBusy, err.kind());
+    assert!(err.to_string().contains(&process::id().to_string()));
+
+    // 释放之后可以再次加锁
+    drop(lock);
+    DirLock::acquire(dir.path())?;
+    Ok(())
+  }
+}
@@ -0,0 +1,294 @@
+use std::{collections::{BTreeMap, HashMap}, ops::RangeBounds, sync::{atomic::{AtomicU64, Ordering}, Arc, RwLock}, time::{Duration, Instant}};
+
+use crate::{engine::{is_empty_range, prefix_entries, prefix_entries_after, KvsEngine}, error::{KvError, Result}};
+
+use super::batch::{BatchOp, WriteBatch};
+
+/// MemoryStore, 只在内存中存放数据的存储引擎
+///
+/// 和KvStore的set、get、remove语义一致，但不会读写任何文件，进程退出后数据就没有了。
+/// 适合测试或者做缓存。
+#[derive(Clone, Default)]
+pub struct MemoryStore {
+  // 数据，和KvStore的索引一样按key排序
+  data: Arc<RwLock<BTreeMap<String, Entry>>>,
+  // 最后分配出去的版本号，只在拿着data的写锁时递增
+  version: Arc<AtomicU64>,
+}
+
+// 一个值和它的过期时间、版本号
+struct Entry {
+  value: String,
+  expires_at: Option<Instant>,
+  version: u64,
+}
+
+impl Entry {
+  fn is_expired(&self, now: Instant) -> bool {
+    matches!(self.expires_at, Some(expires_at) if expires_at <= now)
+  }
+}
+
+impl MemoryStore {
+  pub fn new() -> MemoryStore {
+    MemoryStore::default()
+  }
+
+  // 写入一个值，返回分配的版本号
+  fn insert(&self, data: &mut BTreeMap<String, Entry>, key: String, value: String, expires_at: Option<Instant>) -> u64 {
+    let version = self.version.fetch_add(1, Ordering::SeqCst) + 1;
+    data.insert(key, Entry { value, expires_at, version });
+    version
+  }
+
+  // 拿着写锁检查key当前的值，满足条件才写入，返回新的版本号
+  fn set_if(&self, key: String, value: String, condition: impl FnOnce(Option<&Entry>) -> bool) -> Result<u64> {
+    let mut data = self.data.write().unwrap();
+    let now = Instant::now();
+    if !condition(data.get(&key).filter(|entry| !entry.is_expired(now))) {
+      return Err(KvError::ConditionFailed);
+    }
+    Ok(self.insert(&mut data, key, value, None))
+  }
+}
+
+impl KvsEngine for MemoryStore {
+  fn set(&self, key: String, value: String) -> Result<()> {
+    self.insert(&mut self.data.write().unwrap(), key, value, None);
+    Ok(())
+  }
+
+  fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+    self.insert(&mut self.data.write().unwrap(), key, value, Some(Instant::now() + ttl));
+    Ok(())
+  }
+
+  fn get_versioned(&self, key: String) -> Result<Option<(String, u64)>> {
+    let now = Instant::now();
+    Ok(self.data
+      .read()
+      .unwrap()
+      .get(&key)
+      .filter(|entry| !entry.is_expired(now))
+      .map(|entry| (entry.value.clone(), entry.version)))
+  }
+
+  fn set_if_absent(&self, key: String, value: String) -> Result<u64> {
+    self.set_if(key, value, |current| current.is_none())
+  }
+
+  fn set_if_equals(&self, key: String, expected: String, value: String) -> Result<u64> {
+    self.set_if(key, value, |current| current.is_some_and(|entry| entry.value == expected))
+  }
+
+  fn set_if_version(&self, key: String, version: u64, value: String) -> Result<u64> {
+    self.set_if(key, value, |current| current.is_some_and(|entry| entry.version == version))
+  }
+
+  fn get(&self, key: String) -> Result<Option<String>> {
+    let now = Instant::now();
+    Ok(self.data
+      .read()
+      .unwrap()
+      .get(&key)
+      .filter(|entry| !entry.is_expired(now))
+      .map(|entry| entry.value.clone()))
+  }
+
+  fn remove(&self, key: String) -> Result<()> {
+    // 和KvStore一样，没有找到返回一个错误，过期的key也算没有找到
+    let now = Instant::now();
+    self.data
+      .write()
+      .unwrap()
+      .remove(&key)
+      .filter(|entry| !entry.is_expired(now))
+      .map(|_| ())
+      .ok_or(KvError::KeyNotFound)
+  }
+
+  fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+    let mut data = self.data.write().unwrap();
+    let now = Instant::now();
+    let ops = batch.into_ops();
+    // 先检查remove的key都存在，前面set或者remove过的key看前面的结果，有一个不存在整批都不写入
+    let mut touched = HashMap::new();
+    for op in &ops {
+      match op {
+        BatchOp::Set { key, .. } => {
+          touched.insert(key, true);
+        },
+        BatchOp::Remove { key } => {
+          let exists = touched
+            .get(key)
+            .cloned()
+            .unwrap_or_else(|| data.get(key).is_some_and(|entry| !entry.is_expired(now)));
+          if !exists {
+            return Err(KvError::KeyNotFound);
+          }
+          touched.insert(key, false);
+        },
+      }
+    }
+    // 一直拿着写锁，别的线程看不到只写了一部分的数据
+    for op in ops {
+      match op {
+        BatchOp::Set { key, value, ttl } => {
+          self.insert(&mut data, key, value, ttl.map(|ttl| now + ttl));
+        },
+        BatchOp::Remove { key } => {
+          data.remove(&key);
+        },
+      }
+    }
+    Ok(())
+  }
+
+  fn scan<R: RangeBounds<String>>(&self, range: R, limit: usize) -> Result<Vec<(String, String)>> {
+    if is_empty_range(&range) {
+      return Ok(Vec::new());
+    }
+    let now = Instant::now();
+    Ok(self.data
+      .read()
+      .unwrap()
+      .range(range)
+      .filter(|(_, entry)| !entry.is_expired(now))
+      .take(limit)
+      .map(|(key, entry)| (key.clone(), entry.value.clone()))
+      .collect())
+  }
+
+  fn keys(&self, prefix: &str) -> Result<Vec<String>> {
+    let now = Instant::now();
+    Ok(prefix_entries(&self.data.read().unwrap(), prefix)
+      .filter(|(_, entry)| !entry.is_expired(now))
+      .map(|(key, _)| key.clone())
+      .collect())
+  }
+
+  fn keys_after(&self, prefix: &str, after: Option<&str>, limit: usize) -> Result<Vec<String>> {
+    let now = Instant::now();
+    Ok(prefix_entries_after(&self.data.read().unwrap(), prefix, after)
+      .filter(|(_, entry)| !entry.is_expired(now))
+      .take(limit)
+      .map(|(key, _)| key.clone())
+      .collect())
+  }
+
+  fn count(&self, prefix: &str) -> Result<usize> {
+    let now = Instant::now();
+    Ok(prefix_entries(&self.data.read().unwrap(), prefix)
+      .filter(|(_, entry)| !entry.is_expired(now))
+      .count())
+  }
+
+  // 内存中没有可以压缩的数据，只把过期的key清理掉
+  fn compact(&self) -> Result<()> {
+    let now = Instant::now();
+    self.data.write().unwrap().retain(|_, entry| !entry.is_expired(now));
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use crate::{engine::KvsEngine, error::{KvError, Result}, kv::batch::WriteBatch};
+
+  use super::MemoryStore;
+
+  #[test]
+  fn test_set_get_remove() -> Result<()> {
+    let store = MemoryStore::new();
+    store.set("foo".to_string(), "bar".to_string())?;
+    assert_eq!(Some("bar".to_string()), store.get("foo".to_string())?);
+    store.set("foo".to_string(), "baz".to_string())?;
+    assert_eq!(Some("baz".to_string()), store.get("foo".to_string())?);
+    store.remove("foo".to_string())?;
+    assert_eq!(None, store.get("foo".to_string())?);
+    Ok(())
+  }
+
+  #[test]
+  fn test_scan() -> Result<()> {
+    let store = MemoryStore::new();
+    for key in ["b", "a", "d", "c"] {
+      store.set(key.to_string(), key.to_uppercase())?;
+    }
+    let pairs = |keys: &[&str]| keys.iter().map(|k| (k.to_string(), k.to_uppercase())).collect::<Vec<_>>();
+    assert_eq!(pairs(&["a", "b", "c", "d"]), store.scan(.., 10)?);
+    assert_eq!(pairs(&["b", "c"]), store.scan("b".to_string().."d".to_string(), 10)?);
+    assert_eq!(pairs(&["b"]), store.scan("b".to_string().., 1)?);
+    assert!(store.scan("d".to_string().."a".to_string(), 10)?.is_empty());
+    Ok(())
+  }
+
+  #[test]
+  fn test_keys() -> Result<()> {
+    let store = MemoryStore::new();
+    for key in ["t1/a", "t2/b", "t1/b", "t10/a"] {
+      store.set(key.to_string(), "value".to_string())?;
+    }
+    assert_eq!(vec!["t1/a", "t1/b"], store.keys("t1/")?);
+    assert_eq!(3, store.count("t1")?);
+    assert_eq!(4, store.keys("")?.len());
+    assert_eq!(0, store.count("t3")?);
+    assert_eq!(vec!["t1/b"], store.keys_after("t1/", Some("t1/a"), 10)?);
+    assert_eq!(vec!["t1/a", "t1/b"], store.keys_after("t1/", Some("t0"), 10)?);
+    Ok(())
+  }
+
+  #[test]
+  fn test_ttl() -> Result<()> {
+    let store = MemoryStore::new();
+    store.set_with_ttl("expired".to_string(), "value".to_string(), Duration::ZERO)?;
+    store.set_with_ttl("alive".to_string(), "value".to_string(), Duration::from_secs(60))?;
+    assert_eq!(None, store.get("expired".to_string())?);
+    assert_eq!(Some("value".to_string()), store.get("alive".to_string())?);
+    assert_eq!(vec!["alive"], store.keys("")?);
+    assert_eq!(1, store.scan(.., 10)?.len());
+    assert!(matches!(store.remove("expired".to_string()), Err(KvError::KeyNotFound)));
+    // 重新set之后不再过期
+    store.set("alive".to_string(), "new".to_string())?;
+    store.compact()?;
+    assert_eq!(1, store.data.read().unwrap().len());
+    Ok(())
+  }
+
+  #[test]
+  fn test_conditional_set() -> Result<()> {
+    let store = MemoryStore::new();
+    let version = store.set_if_absent("key".to_string(), "a".to_string())?;
+    assert!(matches!(store.set_if_absent("key".to_string(), "b".to_string()), Err(KvError::ConditionFailed)));
+    assert!(matches!(store.set_if_equals("key".to_string(), "b".to_string(), "c".to_string()), Err(KvError::ConditionFailed)));
+    let new_version = store.set_if_equals("key".to_string(), "a".to_string(), "c".to_string())?;
+    assert!(new_version > version);
+    assert!(matches!(store.set_if_version("key".to_string(), version, "d".to_string()), Err(KvError::ConditionFailed)));
+    store.set_if_version("key".to_string(), new_version, "d".to_string())?;
+    assert_eq!(Some("d".to_string()), store.get("key".to_string())?);
+    Ok(())
+  }
+
+  #[test]
+  fn test_write_batch() -> Result<()> {
+    let store = MemoryStore::new();
+    store.set("a".to_string(), "1".to_string())?;
+    store.write_batch(WriteBatch::new().remove("a".to_string()).set("b".to_string(), "2".to_string()))?;
+    assert_eq!(None, store.get("a".to_string())?);
+    assert_eq!(Some("2".to_string()), store.get("b".to_string())?);
+    // 有一个remove的key不存在，整批都不写入
+    let batch = WriteBatch::new().set("c".to_string(), "3".to_string()).remove("a".to_string());
+    assert!(matches!(store.write_batch(batch), Err(KvError::KeyNotFound)));
+    assert_eq!(None, store.get("c".to_string())?);
+    Ok(())
+  }
+
+  #[test]
+  fn test_remove_not_found() {
+    let store = MemoryStore::new();
+    let err = store.remove("foo".to_string()).unwrap_err();
+    assert!(matches!(err, KvError::KeyNotFound));
+  }
+}
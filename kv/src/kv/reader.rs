@@ -0,0 +1,118 @@
+use std::{collections::HashMap, fs::File, io::{Error, Result}, sync::{Arc, RwLock}};
+
+use super::{command::Command, record::{FileFormat, Record, RecordType}};
+
+/// 一个数据文件和它的格式
+pub struct DataFile {
+  // 文件名
+  pub name: u32,
+  pub file: File,
+  pub format: FileFormat,
+}
+
+impl DataFile {
+  /// 读取索引指向的一条记录
+  ///
+  /// 旧格式的文件中没有记录头，读出来的json直接当作记录的数据，时间戳为0。
+  pub fn read_record(&self, pos: u64, len: u64) -> Result<Record> {
+    let buf = read_at(&self.file, pos, len)?;
+    let record = match self.format {
+      FileFormat::Json => {
+        // 先确认是一条完整的Command
+        serde_json::from_slice::<Command>(&buf)?;
+        Ok(Record { kind: RecordType::Command, timestamp: 0, payload: buf })
+      },
+      FileFormat::Record(_) => Record::decode(&buf),
+    };
+    // 错误信息中带上文件和位置
+    record.map_err(|e| Error::new(e.kind(), format!("{}.log 位置{}: {}", self.name, pos, e)))
+  }
+
+  /// 读取索引指向的Command
+  pub fn read_command(&self, pos: u64, len: u64) -> Result<Command> {
+    self.read_record(pos, len)?.to_command()
+  }
+}
+
+/// 数据文件的reader集合，key: 文件名, value: 数据文件
+///
+/// 读数据用的是按位置读（pread），不会移动文件指针，所以同一个文件可以被多个线程同时读，不需要加锁。
+/// 取文件的时候clone一份`Arc<DataFile>`，压缩删掉旧文件后，正在读的线程手上的文件依然可用。
+#[derive(Clone, Default)]
+pub struct Readers {
+  files: Arc<RwLock<HashMap<u32, Arc<DataFile>>>>,
+}
+
+impl Readers {
+  pub fn insert(&self, file_name: u32, file: File, format: FileFormat) {
+    self.files.write().unwrap().insert(file_name, Arc::new(DataFile { name: file_name, file, format }));
+  }
+
+  pub fn get(&self, file_name: u32) -> Option<Arc<DataFile>> {
+    self.files.read().unwrap().get(&file_name).cloned()
+  }
+
+  pub fn remove(&self, file_name: u32) -> Option<Arc<DataFile>> {
+    self.files.write().unwrap().remove(&file_name)
+  }
+
+  /// 所有的文件名
+  pub fn file_names(&self) -> Vec<u32> {
+    self.files.read().unwrap().keys().cloned().collect()
+  }
+}
+
+/// 从文件的pos位置读取len长度的数据
+pub fn read_at(file: &File, pos: u64, len: u64) -> Result<Vec<u8>> {
+  let mut buf = vec![0; len as usize];
+  read_exact_at(file, &mut buf, pos)?;
+  Ok(buf)
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], pos: u64) -> Result<()> {
+  use std::os::unix::fs::FileExt;
+  file.read_exact_at(buf, pos)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut pos: u64) -> Result<()> {
+  use std::{io::{Error, ErrorKind}, os::windows::fs::FileExt};
+  // windows下的seek_read会移动文件指针，但读的位置只由pos决定，多个线程同时读也没有问题
+  while !buf.is_empty() {
+    match file.seek_read(buf, pos)? {
+      0 => return Err(Error::from(ErrorKind::UnexpectedEof)),
+      n => {
+        buf = &mut buf[n..];
+        pos += n as u64;
+      }
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{fs::File, io::{Result, Write}, sync::Arc, thread};
+
+  use tempfile::TempDir;
+
+  use super::read_at;
+
+  #[test]
+  fn test_read_at_from_threads() -> Result<()> {
+    let dir = TempDir::new()?;
+    let path = dir.path().join("1.log");
+    File::create(&path)?.write_all(b"0123456789")?;
+    let file = Arc::new(File::open(&path)?);
+    let handles = (0..5u64).map(|i| {
+      let file = Arc::clone(&file);
+      thread::spawn(move || read_at(&file, i * 2, 2))
+    }).collect::<Vec<_>>();
+    for (i, handle) in handles.into_iter().enumerate() {
+      let expect = format!("{}{}", i * 2, i * 2 + 1);
+      assert_eq!(expect.as_bytes(), handle.join().unwrap()?.as_slice());
+    }
+    Ok(())
+  }
+}
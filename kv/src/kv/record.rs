@@ -0,0 +1,307 @@
+use std::{io::{Error, ErrorKind, Read, Result}, time::{SystemTime, UNIX_EPOCH}};
+
+use super::command::Command;
+
+/// 数据文件开头的标记，后面跟着2个字节的格式版本号
+///
+/// 没有这个标记的文件是最早的格式：json格式的Command直接首尾相连。
+pub const FILE_MAGIC: &[u8; 6] = b"KVSLOG";
+/// 当前的数据文件格式版本
+pub const FILE_VERSION: u16 = 2;
+/// 文件头的长度：标记 + 版本号
+pub const FILE_HEADER_LEN: u64 = FILE_MAGIC.len() as u64 + 2;
+
+/// 每条记录头的长度
+/// crc32(4) + 数据长度(4) + 记录类型(1) + 时间戳(8) + 记录头crc32(4)
+pub const RECORD_HEADER_LEN: u64 = 4 + 4 + 1 + 8 + 4;
+
+/// 数据文件的格式
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileFormat {
+  /// 旧格式，json直接首尾相连
+  Json,
+  /// 带文件头的记录格式，记录头中有长度和crc校验
+  Record(u16),
+}
+
+impl FileFormat {
+  /// 根据文件开头的数据判断格式，数据不足文件头长度的也按旧格式处理
+  pub fn detect(head: &[u8]) -> FileFormat {
+    if head.len() as u64 >= FILE_HEADER_LEN && head.starts_with(FILE_MAGIC) {
+      let version = [head[FILE_MAGIC.len()], head[FILE_MAGIC.len() + 1]];
+      FileFormat::Record(u16::from_le_bytes(version))
+    } else {
+      FileFormat::Json
+    }
+  }
+}
+
+/// 文件头：标记 + 版本号
+pub fn file_header() -> Vec<u8> {
+  let mut header = FILE_MAGIC.to_vec();
+  header.extend_from_slice(&FILE_VERSION.to_le_bytes());
+  header
+}
+
+/// 记录的类型
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum RecordType {
+  /// 数据是一条json格式的Command
+  Command = 1,
+  /// 批量写入的开始，数据是u32的指令数量，后面紧跟着这么多条Command记录
+  Batch = 2,
+}
+
+impl TryFrom<u8> for RecordType {
+  type Error = Error;
+
+  fn try_from(value: u8) -> Result<Self> {
+    match value {
+      1 => Ok(RecordType::Command),
+      2 => Ok(RecordType::Batch),
+      _ => Err(corruption(format!("未知的记录类型: {}", value))),
+    }
+  }
+}
+
+/// 数据文件中的一条记录
+///
+/// 格式：crc32 | 数据长度 | 记录类型 | 时间戳(毫秒) | 记录头crc32 | 数据，数字都是小端序。
+/// crc32校验的是crc32后面的所有内容，任何一个字节出错都能发现。
+/// 记录头crc32只校验数据长度、记录类型和时间戳，读数据之前先确认长度没坏，
+/// 长度坏了读到文件末尾也读不够，不能当成写了一半的记录截掉。
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+  pub kind: RecordType,
+  // 写入时间，unix时间戳毫秒
+  pub timestamp: u64,
+  pub payload: Vec<u8>,
+}
+
+impl Record {
+  /// 用Command生成一条记录，时间戳为当前时间
+  pub fn command(cmd: &Command) -> Result<Record> {
+    Ok(Record {
+      kind: RecordType::Command,
+      timestamp: now_millis(),
+      payload: serde_json::to_vec(cmd)?,
+    })
+  }
+
+  /// 批量写入的开始记录，后面要跟着count条Command记录
+  pub fn batch(count: usize) -> Record {
+    Record {
+      kind: RecordType::Batch,
+      timestamp: now_millis(),
+      payload: (count as u32).to_le_bytes().to_vec(),
+    }
+  }
+
+  /// 批量写入的开始记录中的指令数量
+  pub fn batch_count(&self) -> Result<usize> {
+    let count: [u8; 4] = self.payload.as_slice().try_into().map_err(|_| corruption(format!("批量写入记录的长度不对: {}", self.payload.len())))?;
+    Ok(u32::from_le_bytes(count) as usize)
+  }
+
+  /// 记录中的Command
+  pub fn to_command(&self) -> Result<Command> {
+    Ok(serde_json::from_slice(&self.payload)?)
+  }
+
+  /// 编码后的字节
+  pub fn encode(&self) -> Vec<u8> {
+    let mut body = Vec::with_capacity(RECORD_HEADER_LEN as usize + self.payload.len() - 4);
+    body.extend_from_slice(&(self.payload.len() as u32).to_le_bytes());
+    body.push(self.kind as u8);
+    body.extend_from_slice(&self.timestamp.to_le_bytes());
+    let header_crc = crc32fast::hash(&body);
+    body.extend_from_slice(&header_crc.to_le_bytes());
+    body.extend_from_slice(&self.payload);
+
+    let mut buf = Vec::with_capacity(body.len() + 4);
+    buf.extend_from_slice(&crc32fast::hash(&body).to_le_bytes());
+    buf.extend_from_slice(&body);
+    buf
+  }
+
+  /// 解码一条完整的记录，长度或者crc校验不对都返回`ErrorKind::InvalidData`
+  pub fn decode(buf: &[u8]) -> Result<Record> {
+    if (buf.len() as u64) < RECORD_HEADER_LEN {
+      return Err(corruption(format!("记录长度不足: {}", buf.len())));
+    }
+    check_header(&buf[..RECORD_HEADER_LEN as usize])?;
+    let crc = u32::from_le_bytes(buf[0..4].try_into().unwrap());
+    let len = u32::from_le_bytes(buf[4..8].try_into().unwrap()) as u64;
+    if RECORD_HEADER_LEN + len != buf.len() as u64 {
+      return Err(corruption(format!("记录长度不一致: 记录头中为{}，实际为{}", len, buf.len() as u64 - RECORD_HEADER_LEN)));
+    }
+    if crc32fast::hash(&buf[4..]) != crc {
+      return Err(corruption("crc校验失败".to_string()));
+    }
+    Ok(Record {
+      kind: RecordType::try_from(buf[8])?,
+      timestamp: u64::from_le_bytes(buf[9..17].try_into().unwrap()),
+      payload: buf[RECORD_HEADER_LEN as usize..].to_vec(),
+    })
+  }
+
+  /// 从reader中读一条记录，返回记录和它占用的字节数
+  ///
+  /// 正好读到文件末尾返回`None`，记录不完整返回`ErrorKind::UnexpectedEof`，
+  /// 记录内容有问题返回`ErrorKind::InvalidData`。记录头校验失败时不再往后读。
+  pub fn read_from<R: Read>(reader: &mut R) -> Result<Option<(Record, u64)>> {
+    let mut header = [0; RECORD_HEADER_LEN as usize];
+    // 先读一个字节，区分文件正常结束和记录头不完整
+    if reader.read(&mut header[..1])? == 0 {
+      return Ok(None);
+    }
+    reader.read_exact(&mut header[1..])?;
+    check_header(&header)?;
+    let len = u32::from_le_bytes(header[4..8].try_into().unwrap()) as u64;
+    let mut buf = header.to_vec();
+    // 长度可能就是坏的，不能直接按它分配内存
+    let read = reader.take(len).read_to_end(&mut buf)? as u64;
+    if read < len {
+      return Err(Error::from(ErrorKind::UnexpectedEof));
+    }
+    let record = Record::decode(&buf)?;
+    Ok(Some((record, RECORD_HEADER_LEN + len)))
+  }
+}
+
+// 校验记录头中的数据长度、记录类型和时间戳
+fn check_header(header: &[u8]) -> Result<()> {
+  let header_crc = u32::from_le_bytes(header[17..21].try_into().unwrap());
+  if crc32fast::hash(&header[4..17]) != header_crc {
+    return Err(corruption("记录头crc校验失败".to_string()));
+  }
+  Ok(())
+}
+
+/// 按顺序读取索引文件中的字段，长度不够时返回`ErrorKind::InvalidData`
+pub struct FieldReader<'a> {
+  buf: &'a [u8],
+}
+
+impl<'a> FieldReader<'a> {
+  pub fn new(buf: &'a [u8]) -> FieldReader<'a> {
+    FieldReader { buf }
+  }
+
+  pub fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+    if self.buf.len() < len {
+      return Err(corruption("索引文件长度不足".to_string()));
+    }
+    let (head, rest) = self.buf.split_at(len);
+    self.buf = rest;
+    Ok(head)
+  }
+
+  pub fn u16(&mut self) -> Result<u16> {
+    Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+  }
+
+  pub fn u32(&mut self) -> Result<u32> {
+    Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+  }
+
+  pub fn u64(&mut self) -> Result<u64> {
+    Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+  }
+
+  /// u32长度开头的utf8字符串
+  pub fn string(&mut self) -> Result<String> {
+    let len = self.u32()? as usize;
+    String::from_utf8(self.take(len)?.to_vec()).map_err(|e| corruption(e.to_string()))
+  }
+}
+
+/// 数据损坏的错误
+pub fn corruption(msg: String) -> Error {
+  Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// 当前时间，unix时间戳毫秒
+pub fn now_millis() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|d| d.as_millis() as u64)
+    .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::{ErrorKind, Result};
+
+  use crate::kv::command::Command;
+
+  use super::{file_header, FileFormat, Record, FILE_VERSION};
+
+  fn set_record() -> Result<Record> {
+    Record::command(&Command::set("key".to_string(), "value".to_string()))
+  }
+
+  #[test]
+  fn test_encode_decode() -> Result<()> {
+    let record = set_record()?;
+    let buf = record.encode();
+    assert_eq!(record, Record::decode(&buf)?);
+    assert!(matches!(record.to_command()?, Command::Set { key, value, .. } if key == "key" && value == "value"));
+    Ok(())
+  }
+
+  #[test]
+  fn test_decode_corrupted() -> Result<()> {
+    let mut buf = set_record()?.encode();
+    // 改掉数据中的一个字节
+    let last = buf.len() - 2;
+    buf[last] ^= 0xff;
+    assert_eq!(ErrorKind::InvalidData, Record::decode(&buf).unwrap_err().kind());
+    Ok(())
+  }
+
+  #[test]
+  fn test_read_from() -> Result<()> {
+    let record = set_record()?;
+    let mut data = record.encode();
+    data.extend_from_slice(&record.encode());
+    let mut reader = data.as_slice();
+    assert_eq!(Some((record.clone(), data.len() as u64 / 2)), Record::read_from(&mut reader)?);
+    assert!(Record::read_from(&mut reader)?.is_some());
+    assert_eq!(None, Record::read_from(&mut reader)?);
+
+    // 最后一条记录不完整
+    let mut reader = &data[..data.len() - 3];
+    Record::read_from(&mut reader)?;
+    assert_eq!(ErrorKind::UnexpectedEof, Record::read_from(&mut reader).unwrap_err().kind());
+    Ok(())
+  }
+
+  #[test]
+  fn test_corrupted_length() -> Result<()> {
+    let record = set_record()?;
+    let mut data = record.encode();
+    data.extend_from_slice(&record.encode());
+    // 长度坏了，按它读会一直读到文件末尾，不能当成不完整的记录
+    data[5] ^= 0x01;
+    let mut reader = data.as_slice();
+    assert_eq!(ErrorKind::InvalidData, Record::read_from(&mut reader).unwrap_err().kind());
+    Ok(())
+  }
+
+  #[test]
+  fn test_batch_record() -> Result<()> {
+    let record = Record::batch(3);
+    assert_eq!(3, Record::decode(&record.encode())?.batch_count()?);
+    assert_eq!(ErrorKind::InvalidData, set_record()?.batch_count().unwrap_err().kind());
+    Ok(())
+  }
+
+  #[test]
+  fn test_detect_format() {
+    assert_eq!(FileFormat::Record(FILE_VERSION), FileFormat::detect(&file_header()));
+    assert_eq!(FileFormat::Json, FileFormat::detect(br#"{"Set":{"key":"k","value":"v"}}"#));
+    assert_eq!(FileFormat::Json, FileFormat::detect(b""));
+  }
+}
@@ -1,4 +1,4 @@
-use std::io::{BufWriter, Result, Seek, SeekFrom, Write};
+use std::{fs::File, io::{BufWriter, Result, Seek, SeekFrom, Write}, mem};
 
 /// 就如effective rust里说的那样，远离过度优化的诱惑，其实File已经实现了Write 和 Seek，我觉得完全可以代替bufwriter,但既然是在练习rust，能多写点就多写点吧。
 pub struct WriterWithPos<W: Write + Seek> {
@@ -8,6 +8,10 @@ pub struct WriterWithPos<W: Write + Seek> {
 
   // 每次写完的位置
   pub pos: u64,
+
+  // 测试用，写到这个位置就返回错误，模拟磁盘写满，前面的部分会写到文件中
+  #[cfg(test)]
+  pub fail_at: Option<u64>,
 }
 
 impl<W: Write + Seek> WriterWithPos<W> {
@@ -20,12 +24,40 @@ impl<W: Write + Seek> WriterWithPos<W> {
     Ok(WriterWithPos {
       writer: BufWriter::new(inner),
       pos,
+      #[cfg(test)]
+      fail_at: None,
     })
   }
 }
 
+impl WriterWithPos<File> {
+  /// 把缓冲区的数据写到文件，并且让系统把文件数据真正写到磁盘上（fsync）
+  pub fn sync(&mut self) -> Result<()> {
+    self.writer.flush()?;
+    self.writer.get_ref().sync_data()
+  }
+
+  /// 写入失败后回滚到start：丢掉缓冲区中还没写到文件的数据，再把文件截断到start
+  pub fn rollback(&mut self, start: u64) -> Result<()> {
+    let file = self.writer.get_ref().try_clone()?;
+    // into_parts不会把缓冲区中的数据写到文件
+    let _ = mem::replace(&mut self.writer, BufWriter::new(file)).into_parts();
+    self.writer.get_ref().set_len(start)?;
+    self.pos = start;
+    Ok(())
+  }
+}
+
 impl<W: Write + Seek> Write for WriterWithPos<W> {
   fn write(&mut self, buf: &[u8]) -> Result<usize> {
+    #[cfg(test)]
+    if let Some(fail_at) = self.fail_at.filter(|&at| self.pos + buf.len() as u64 > at) {
+      let len = fail_at.saturating_sub(self.pos) as usize;
+      self.writer.write_all(&buf[..len])?;
+      self.writer.flush()?;
+      self.pos += len as u64;
+      return Err(std::io::Error::from(std::io::ErrorKind::StorageFull));
+    }
 
     // 写入的数据长度
     let write_len = self.writer.write(buf)?;
@@ -43,18 +75,21 @@ impl<W: Write + Seek> Write for WriterWithPos<W> {
 
 #[cfg(test)]
 mod tests {
-    use std::{env::current_dir, fs::File, io::{self, Seek}};
+    use std::{fs::File, io::{self, Seek, Write}};
+
+    use tempfile::TempDir;
 
   #[test]
   fn test_seek() -> io::Result<()> {
-    let mut file = File::open(current_dir()?.join("data.log"))?;
+    let dir = TempDir::new()?;
+    File::create(dir.path().join("data.log"))?.write_all(b"{}")?;
+    let mut file = File::open(dir.path().join("data.log"))?;
     let seek_end = file.seek(io::SeekFrom::End(0))?;
     let seek_start = file.seek(io::SeekFrom::Start(0))?;
-    let seek_cur = file.seek(io::SeekFrom::Current(0))?;
+    let seek_cur = file.stream_position()?;
 
     println!("seek start: {}, end: {}, current: {}", seek_start, seek_end, seek_cur);
 
     Ok(())
   }
 }
-
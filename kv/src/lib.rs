@@ -1,3 +1,14 @@
+pub mod client;
+pub mod engine;
+pub mod error;
 pub mod kv;
 pub mod server;
 pub mod req;
+pub mod thread_pool;
+
+#[cfg(feature = "async")]
+mod async_io;
+#[cfg(feature = "async")]
+pub mod async_client;
+#[cfg(feature = "async")]
+pub mod async_server;
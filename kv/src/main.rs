@@ -1,5 +1,24 @@
-use kv::server::KvServer;
+use std::time::Duration;
+
+use clap::Parser;
+use kv::{kv::{memory::MemoryStore, KvStore}, server::{Engine, KvServer, ServerCli}};
 
 fn main() {
-  KvServer::new().unwrap().start().unwrap();
+  let cli = ServerCli::parse();
+  let config = cli.config();
+
+  match cli.engine {
+    Engine::Kv => {
+      let mut builder = KvStore::builder(&cli.data_dir)
+        .durability(cli.durability)
+        .compaction_policy(cli.compaction)
+        .compaction_min_interval(Duration::from_secs(cli.compaction_interval));
+      if cli.checkpoint_interval > 0 {
+        builder = builder.checkpoint_interval(Duration::from_secs(cli.checkpoint_interval));
+      }
+      let store = builder.open().unwrap();
+      KvServer::new(store, config).unwrap().start().unwrap()
+    },
+    Engine::Memory => KvServer::new(MemoryStore::new(), config).unwrap().start().unwrap(),
+  }
 }
@@ -1,6 +1,6 @@
 use serde::{Deserialize, Serialize};
 
-use crate::kv::command::Command;
+use crate::{error::{ErrorCode, KvError}, kv::command::Command};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Request {
@@ -9,5 +9,62 @@ pub struct Request {
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Response {
-  pub result: Result<Option<String>, String>
-}
\ No newline at end of file
+  pub result: Result<Option<String>, ErrorResponse>,
+  /// result中放不下的数据，比如scan的结果，其它请求没有这个字段
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub body: Option<Body>,
+}
+
+impl Response {
+  /// 失败的响应
+  pub fn error(e: KvError) -> Response {
+    Response { result: Err(e.into()), body: None }
+  }
+
+  /// 取出响应中附带的数据，失败的响应按错误码转成对应的错误
+  pub fn into_body(self) -> crate::error::Result<Body> {
+    self.result.map_err(KvError::from)?;
+    self.body.ok_or_else(|| KvError::Protocol("响应中没有数据".to_string()))
+  }
+}
+
+/// 响应中附带的数据
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum Body {
+  Scan(ScanPage),
+  /// keys的结果分成多个响应发送，每个响应带一部分key，最后一个响应的more是false
+  Keys { keys: Vec<String>, more: bool },
+  Count(usize),
+  /// 数据的版本号，get-versioned和条件写的结果
+  Version(u64),
+}
+
+/// scan的一页结果
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ScanPage {
+  /// 按key排好序的键值对
+  pub items: Vec<(String, String)>,
+  /// 这一页取满了时是这一页最后一个key，作为下一页请求的cursor，没取满说明取完了，是`None`
+  ///
+  /// 取满时后面不一定还有数据，下一页可能是空的。
+  pub cursor: Option<String>,
+}
+
+/// 服务端返回的错误：错误码和给人看的错误信息，客户端根据错误码判断错误的类型
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ErrorResponse {
+  pub code: ErrorCode,
+  pub message: String,
+}
+
+impl From<KvError> for ErrorResponse {
+  fn from(e: KvError) -> Self {
+    ErrorResponse { code: e.code(), message: e.to_string() }
+  }
+}
+
+impl From<ErrorResponse> for KvError {
+  fn from(e: ErrorResponse) -> Self {
+    KvError::remote(e.code, e.message)
+  }
+}
@@ -1,113 +1,465 @@
 
-use std::{io::{BufReader, BufWriter, Result, Write}, net::{TcpListener, TcpStream}};
+use std::{io::{BufReader, BufWriter, Write}, net::{SocketAddr, TcpListener, TcpStream}, ops::Bound, path::PathBuf, thread};
 
+use clap::{Parser, ValueEnum};
 use serde_json::Deserializer;
 
-use crate::{kv::{command::Command, KvStore}, req::{Request, Response}};
+use crate::{engine::KvsEngine, error::{KvError, Result}, kv::{batch::WriteBatch, builder::{CompactionPolicy, Durability}, command::{request_ttl, Command}}, req::{Body, ErrorResponse, Request, Response, ScanPage}, thread_pool::ThreadPool};
 
-const SERVER_PORT: &str = "127.0.0.1:4000";
+pub(crate) const SERVER_PORT: &str = "127.0.0.1:4000";
 
-pub struct KvServer {
-  store: KvStore,
+/// scan请求没有指定limit时一页返回的数量
+pub(crate) const DEFAULT_SCAN_LIMIT: usize = 100;
+/// scan一页最多返回的数量，避免一个响应太大
+pub(crate) const MAX_SCAN_LIMIT: usize = 1000;
+/// keys的结果分成多个响应发送，一个响应中最多的key的数量
+pub(crate) const KEYS_CHUNK_SIZE: usize = 1000;
+
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+pub struct ServerCli {
+  /// 监听的地址，例如 0.0.0.0:4000
+  #[arg(short, long, value_name = "IP:PORT", default_value = SERVER_PORT)]
+  pub addr: SocketAddr,
+
+  /// 数据文件目录，使用memory引擎时不需要
+  #[arg(short, long, value_name = "DIR", default_value = "data")]
+  pub data_dir: PathBuf,
+
+  /// 写数据后什么时候fsync：always、never，或者间隔毫秒数如100ms。服务在满足这个策略后才会回复ok
+  #[arg(long, value_name = "POLICY", default_value_t = Durability::Never)]
+  pub durability: Durability,
+
+  /// 什么时候自动压缩：manual、bytes:字节数，或者ratio:可压缩数据和有效数据的比例如ratio:1.5
+  #[arg(long, value_name = "POLICY", default_value_t = CompactionPolicy::default())]
+  pub compaction: CompactionPolicy,
+
+  /// 两次自动压缩之间最短的间隔秒数
+  #[arg(long, value_name = "SECONDS", default_value_t = 0)]
+  pub compaction_interval: u64,
+
+  /// 每隔多少秒写一次索引快照，重启时只回放快照之后的数据，0表示不写
+  #[arg(long, value_name = "SECONDS", default_value_t = 0)]
+  pub checkpoint_interval: u64,
+
+  /// 使用的存储引擎
+  #[arg(short, long, value_enum, default_value_t = Engine::Kv)]
+  pub engine: Engine,
+
+  /// 处理连接的工作线程数量，默认为CPU核数
+  #[arg(short, long)]
+  pub threads: Option<usize>,
+}
+
+impl ServerCli {
+  /// 命令行参数转成服务的配置
+  pub fn config(&self) -> ServerConfig {
+    let default = ServerConfig::default();
+    ServerConfig {
+      addr: self.addr,
+      threads: self.threads.unwrap_or(default.threads),
+    }
+  }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Engine {
+  /// 数据写入日志文件的KvStore
+  Kv,
+  /// 只存放在内存中的MemoryStore
+  Memory,
+}
+
+/// 服务的配置
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+  // 监听的地址
+  pub addr: SocketAddr,
+  // 处理连接的工作线程数量
+  pub threads: usize,
 }
 
-impl KvServer {
-  pub fn new() -> Result<KvServer> {
-      Ok(KvServer { store: KvStore::open()? })
+impl Default for ServerConfig {
+  fn default() -> Self {
+    ServerConfig {
+      addr: SERVER_PORT.parse().expect("默认地址格式错误！"),
+      threads: thread::available_parallelism().map(|n| n.get()).unwrap_or(4),
+    }
   }
+}
+
+pub struct KvServer<E: KvsEngine> {
+  store: E,
+  // 监听的地址
+  addr: SocketAddr,
+  // 处理连接的线程池
+  pool: ThreadPool,
+}
 
-  pub fn start(&mut self) -> Result<()> {
-    let tcp_listener = TcpListener::bind(SERVER_PORT)?;
+impl<E: KvsEngine> KvServer<E> {
+  pub fn new(store: E, config: ServerConfig) -> Result<KvServer<E>> {
+      Ok(KvServer { store, addr: config.addr, pool: ThreadPool::new(config.threads)? })
+  }
+
+  pub fn start(&self) -> Result<()> {
+    self.serve(TcpListener::bind(self.addr)?)
+  }
+
+  /// 在已经绑定好的listener上处理请求，每个连接交给线程池中的一个工作线程
+  pub fn serve(&self, tcp_listener: TcpListener) -> Result<()> {
     for stream in tcp_listener.incoming() {
       match stream {
         Ok(stream) => {
-          if let Err(e) = self.handle_connection(stream) {
-            println!("请求错误！{}", e);
-          }
+          let store = self.store.clone();
+          self.pool.spawn(move || {
+            if let Err(e) = handle_connection(store, stream) {
+              println!("请求错误！{}", e);
+            }
+          });
         },
         Err(e) => println!("网络连接错误！{}", e),
       }
     }
     Ok(())
   }
+}
 
-  fn handle_connection(&mut self, stream: TcpStream) -> Result<()> {
+fn handle_connection<E: KvsEngine>(store: E, stream: TcpStream) -> Result<()> {
     let peer_addr = stream.peer_addr()?;
     println!("from: {}", peer_addr);
 
     let reader = Deserializer::from_reader(BufReader::new(&stream));
     let mut writer = BufWriter::new(&stream);
 
-    for reqeust in reader.into_iter::<Request>().flatten() {
-      println!("command: {}", serde_json::to_string(&reqeust.command)?);
-      match reqeust.command {
-        Command::Set { key, value } => {
-          let set = self.store
-            .set(key, value)
-            .map(|_|Some("ok".to_string()))
-            .map_err(|e| format!("{e}"));
-
-          serde_json::to_writer(&mut writer, &Response{result: set})?;
-          writer.flush()?;
-        },
-        Command::Get { key } => {
-          let get = self.store
-            .get(key)
-            .map_err(|e| format!("{e}"));
-
-          serde_json::to_writer(&mut writer, &Response{result: get})?;
-          writer.flush()?; 
+    for reqeust in reader.into_iter::<Request>() {
+      match reqeust {
+        // 响应一产生就写出去，keys的结果不用都准备好了再发
+        Ok(reqeust) => {
+          println!("command: {}", serde_json::to_string(&reqeust.command)?);
+          execute(&store, reqeust.command, |response| Ok(serde_json::to_writer(&mut writer, &response)?))?;
         },
-        Command::Remove { key } => {
-          let remove = self.store
-            .remove(key)
-            .map(|_|Some("ok".to_string()))
-            .map_err(|e| format!("{e}"));
-
-          serde_json::to_writer(&mut writer, &Response{result: remove})?;
+        // 连接断开
+        Err(e) if e.is_eof() || e.is_io() => break,
+        // 请求的格式不对，后面的数据也没法解析了，返回错误后关闭连接
+        Err(e) => {
+          let response = Response::error(KvError::Protocol(e.to_string()));
+          serde_json::to_writer(&mut writer, &response)?;
           writer.flush()?;
+          break;
         },
       }
+      writer.flush()?;
     }
     Ok(())
+}
+
+/// 在存储引擎上执行一条指令，按顺序把响应交给respond发回去，阻塞和异步的服务共用
+///
+/// keys的结果可能很多，分成多个响应，每取出一批就发一个，其它指令都只有一个响应。
+/// 只有respond返回的错误才会返回，引擎的错误都放在响应中。
+pub(crate) fn execute<E: KvsEngine>(store: &E, command: Command, mut respond: impl FnMut(Response) -> Result<()>) -> Result<()> {
+  let result = match command {
+    Command::Set { key, value, ttl, expires_at, .. } => {
+      match request_ttl(ttl, expires_at) {
+        Some(ttl) => store.set_with_ttl(key, value, ttl),
+        None => store.set(key, value),
+      }.map(|_|Some("ok".to_string()))
+    },
+    Command::Get { key } => store
+      .get(key),
+    Command::GetVersioned { key } => {
+      return respond(match store.get_versioned(key) {
+        Ok(Some((value, version))) => Response { result: Ok(Some(value)), body: Some(Body::Version(version)) },
+        Ok(None) => Response { result: Ok(None), body: None },
+        Err(e) => Response::error(e),
+      });
+    },
+    Command::SetIfAbsent { key, value } => return respond(version_response(store.set_if_absent(key, value))),
+    Command::SetIfEquals { key, value, expected } => return respond(version_response(store.set_if_equals(key, expected, value))),
+    Command::SetIfVersion { key, value, version } => return respond(version_response(store.set_if_version(key, version, value))),
+    Command::Remove { key } => store
+      .remove(key)
+      .map(|_|Some("ok".to_string())),
+    Command::Compact => store
+      .compact()
+      .map(|_|Some("ok".to_string())),
+    Command::Batch { ops } => WriteBatch::try_from(ops)
+      .and_then(|batch| store.write_batch(batch))
+      .map(|_|Some("ok".to_string())),
+    Command::Scan { start, end, limit, cursor } => {
+      return respond(match scan(store, start, end, limit, cursor) {
+        Ok(page) => Response { result: Ok(None), body: Some(Body::Scan(page)) },
+        Err(e) => Response::error(e),
+      });
+    },
+    Command::Keys { prefix } => return send_keys(store, &prefix, respond),
+    Command::Count { prefix } => {
+      return respond(match store.count(&prefix) {
+        Ok(count) => Response { result: Ok(None), body: Some(Body::Count(count)) },
+        Err(e) => Response::error(e),
+      });
+    },
+  };
+  respond(Response { result: result.map_err(ErrorResponse::from), body: None })
+}
+
+// 条件写的响应，成功时带上新的版本号，条件不满足时是condition_failed错误
+fn version_response(result: Result<u64>) -> Response {
+  match result {
+    Ok(version) => Response { result: Ok(Some("ok".to_string())), body: Some(Body::Version(version)) },
+    Err(e) => Response::error(e),
+  }
+}
+
+// 每次从引擎中取KEYS_CHUNK_SIZE个key，取到就发一个响应，不用把所有的key都放在内存中
+// 一批取满了就认为后面还有，最后可能是一个空的响应，没有key时也要有一个响应告诉客户端结束了
+fn send_keys<E: KvsEngine>(store: &E, prefix: &str, mut respond: impl FnMut(Response) -> Result<()>) -> Result<()> {
+  let mut after = None;
+  loop {
+    let keys = match store.keys_after(prefix, after.as_deref(), KEYS_CHUNK_SIZE) {
+      Ok(keys) => keys,
+      Err(e) => return respond(Response::error(e)),
+    };
+    let more = keys.len() == KEYS_CHUNK_SIZE;
+    after = keys.last().cloned();
+    respond(Response { result: Ok(None), body: Some(Body::Keys { keys, more }) })?;
+    if !more {
+      return Ok(());
+    }
   }
 }
+
+// 取一页[start, end)范围内的键值对，有cursor时从cursor之后开始
+// 一页取满了这一页最后一个key就是下一页的cursor，不多取一个来判断后面还有没有，
+// 多取的那个可能在下一页请求之前被删掉了，后面还有的数据就翻不到了
+fn scan<E: KvsEngine>(store: &E, start: Option<String>, end: Option<String>, limit: Option<usize>, cursor: Option<String>) -> Result<ScanPage> {
+  let limit = limit.unwrap_or(DEFAULT_SCAN_LIMIT).min(MAX_SCAN_LIMIT);
+  let start = match (cursor, start) {
+    (Some(cursor), _) => Bound::Excluded(cursor),
+    (None, Some(start)) => Bound::Included(start),
+    (None, None) => Bound::Unbounded,
+  };
+  let end = end.map_or(Bound::Unbounded, Bound::Excluded);
+  let items = store.scan((start, end), limit)?;
+  let cursor = if items.len() == limit {
+    items.last().map(|(key, _)| key.clone())
+  } else {
+    None
+  };
+  Ok(ScanPage { items, cursor })
+}
+
 #[cfg(test)]
 mod test {
-    use std::{io::{self, BufReader, BufWriter, Write}, net::TcpStream};
+    use std::{io::{BufReader, BufWriter, Write}, net::{SocketAddr, TcpListener, TcpStream}, thread, time::Duration};
 
     use serde::Deserialize;
     use serde_json::Deserializer;
 
-    use crate::{kv::command::Command, req::{Request, Response}};
+    use crate::{engine::KvsEngine, error::{ErrorCode, Result}, kv::{command::Command, memory::MemoryStore}, req::{Body, Request, Response, ScanPage}};
+
+    use super::{execute, KvServer, ServerConfig, KEYS_CHUNK_SIZE};
+
+  // 在随机端口上启动一个使用MemoryStore的服务
+  fn start_server() -> Result<SocketAddr> {
+    let server = KvServer::new(MemoryStore::new(), ServerConfig { threads: 2, ..Default::default() })?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    thread::spawn(move || server.serve(listener));
+    Ok(addr)
+  }
+
+  // 连接服务，返回一个发送请求并等待响应的函数
+  fn connect(addr: SocketAddr) -> Result<impl FnMut(Command) -> Result<Response>> {
+    let tcp_stream = TcpStream::connect(addr)?;
+    let mut writer = BufWriter::new(tcp_stream.try_clone()?);
+    let mut reader = Deserializer::from_reader(BufReader::new(tcp_stream));
+    Ok(move |command| -> Result<Response> {
+      serde_json::to_writer(&mut writer, &Request{command})?;
+      writer.flush()?;
+      Ok(Response::deserialize(&mut reader)?)
+    })
+  }
 
   #[test]
-  fn test_tcp_set() -> io::Result<()> {
-    let tcp_stream = TcpStream::connect("127.0.0.1:4000")?;
-    let mut writer = BufWriter::new(&tcp_stream);
-    let mut reader = Deserializer::from_reader(BufReader::new(&tcp_stream));
+  fn test_start_on_config_addr() -> Result<()> {
+    // 先占一个随机端口拿到地址，再让服务绑定到这个地址上
+    let addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    let server = KvServer::new(MemoryStore::new(), ServerConfig { addr, threads: 1 })?;
+    thread::spawn(move || server.start());
+
+    let mut send = None;
+    for _ in 0..50 {
+      match connect(addr) {
+        Ok(connected) => { send = Some(connected); break; },
+        Err(_) => thread::sleep(Duration::from_millis(20)),
+      }
+    }
+    let mut send = send.expect("服务没有启动");
+    assert_eq!(send(Command::Get { key: "key".to_string() })?.result, Ok(None));
+    Ok(())
+  }
+
+  #[test]
+  fn test_tcp_set() -> Result<()> {
+    let mut send = connect(start_server()?)?;
 
     // set
-    let value = Command::Set { key: "key".to_string(), value: "value".to_string() };
-    serde_json::to_writer(&mut writer, &Request{command: value})?;
-    writer.flush()?;
-    let resp = Response::deserialize(&mut reader)?;
+    let resp = send(Command::set("key".to_string(), "value".to_string()))?;
     assert_eq!(resp.result, Ok(Some("ok".to_string())));
 
     // get
-    let value = Command::Get { key: "key".to_string() };
-    serde_json::to_writer(&mut writer, &Request{command: value})?;
-    writer.flush()?;
-    let resp = Response::deserialize(&mut reader)?;
+    let resp = send(Command::Get { key: "key".to_string() })?;
     assert_eq!(resp.result, Ok(Some("value".to_string())));
 
     // remove
-    let value = Command::Remove { key: "key".to_string() };
-    serde_json::to_writer(&mut writer, &Request{command: value})?;
-    writer.flush()?;
-    let resp = Response::deserialize(&mut reader)?;
+    let resp = send(Command::Remove { key: "key".to_string() })?;
     assert_eq!(resp.result, Ok(Some("ok".to_string())));
 
+    // remove不存在的key
+    let resp = send(Command::Remove { key: "key".to_string() })?;
+    assert_eq!(ErrorCode::KeyNotFound, resp.result.unwrap_err().code);
+
+    // compact
+    let resp = send(Command::Compact)?;
+    assert_eq!(resp.result, Ok(Some("ok".to_string())));
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_idle_client_does_not_block() -> Result<()> {
+    let addr = start_server()?;
+    // 这个连接一直不发请求
+    let _idle = TcpStream::connect(addr)?;
+
+    let mut send = connect(addr)?;
+    let resp = send(Command::Get { key: "key".to_string() })?;
+    assert_eq!(resp.result, Ok(None));
+
     Ok(())
   }
-}
\ No newline at end of file
+
+  #[test]
+  fn test_tcp_scan() -> Result<()> {
+    let mut send = connect(start_server()?)?;
+    for i in 0..5 {
+      send(Command::set(format!("key-{}", i), format!("value-{}", i)))?;
+    }
+
+    // 一页两个，翻页直到cursor为空
+    let mut keys = Vec::new();
+    let mut cursor = None;
+    loop {
+      let resp = send(Command::Scan { start: Some("key-1".to_string()), end: None, limit: Some(2), cursor })?;
+      let Some(Body::Scan(page)) = resp.body else { panic!("没有scan的结果") };
+      keys.extend(page.items.into_iter().map(|(key, _)| key));
+      cursor = page.cursor;
+      if cursor.is_none() {
+        break;
+      }
+    }
+    assert_eq!(vec!["key-1", "key-2", "key-3", "key-4"], keys);
+
+    // 刚好取完时一页是满的，还有cursor，下一页是空的
+    let resp = send(Command::Scan { start: None, end: Some("key-2".to_string()), limit: Some(2), cursor: None })?;
+    let expected = ScanPage { items: vec![("key-0".to_string(), "value-0".to_string()), ("key-1".to_string(), "value-1".to_string())], cursor: Some("key-1".to_string()) };
+    assert_eq!(Some(Body::Scan(expected)), resp.body);
+    let resp = send(Command::Scan { start: None, end: Some("key-2".to_string()), limit: Some(2), cursor: Some("key-1".to_string()) })?;
+    assert_eq!(Some(Body::Scan(ScanPage { items: Vec::new(), cursor: None })), resp.body);
+
+    // 翻页之间删掉了下一页的第一个key，后面的数据还能翻到
+    let resp = send(Command::Scan { start: None, end: None, limit: Some(2), cursor: None })?;
+    let Some(Body::Scan(page)) = resp.body else { panic!("没有scan的结果") };
+    send(Command::Remove { key: "key-2".to_string() })?;
+    let resp = send(Command::Scan { start: None, end: None, limit: Some(2), cursor: page.cursor })?;
+    let Some(Body::Scan(page)) = resp.body else { panic!("没有scan的结果") };
+    assert_eq!(vec!["key-3", "key-4"], page.items.into_iter().map(|(key, _)| key).collect::<Vec<_>>());
+
+    // 其它请求的响应中没有body
+    let resp = send(Command::Get { key: "key-0".to_string() })?;
+    assert_eq!(None, resp.body);
+    Ok(())
+  }
+
+  #[test]
+  fn test_tcp_conditional_set() -> Result<()> {
+    let mut send = connect(start_server()?)?;
+
+    let resp = send(Command::SetIfAbsent { key: "key".to_string(), value: "a".to_string() })?;
+    let Some(Body::Version(version)) = resp.body else { panic!("没有版本号") };
+    let resp = send(Command::SetIfAbsent { key: "key".to_string(), value: "b".to_string() })?;
+    assert_eq!(ErrorCode::ConditionFailed, resp.result.unwrap_err().code);
+
+    let resp = send(Command::GetVersioned { key: "key".to_string() })?;
+    assert_eq!(Ok(Some("a".to_string())), resp.result);
+    assert_eq!(Some(Body::Version(version)), resp.body);
+
+    let resp = send(Command::SetIfVersion { key: "key".to_string(), value: "b".to_string(), version: version + 1 })?;
+    assert_eq!(ErrorCode::ConditionFailed, resp.result.unwrap_err().code);
+    let resp = send(Command::SetIfEquals { key: "key".to_string(), value: "b".to_string(), expected: "a".to_string() })?;
+    assert_eq!(Ok(Some("ok".to_string())), resp.result);
+    Ok(())
+  }
+
+  #[test]
+  fn test_tcp_batch() -> Result<()> {
+    let mut send = connect(start_server()?)?;
+
+    let ops = vec![Command::set("a".to_string(), "1".to_string()), Command::set("b".to_string(), "2".to_string())];
+    assert_eq!(Ok(Some("ok".to_string())), send(Command::Batch { ops })?.result);
+    // 有一个remove的key不存在，整批都不写入
+    let ops = vec![Command::Remove { key: "a".to_string() }, Command::Remove { key: "c".to_string() }];
+    assert_eq!(ErrorCode::KeyNotFound, send(Command::Batch { ops })?.result.unwrap_err().code);
+    assert_eq!(Ok(Some("1".to_string())), send(Command::Get { key: "a".to_string() })?.result);
+    // 批量写入中只能有set和remove
+    let ops = vec![Command::Get { key: "a".to_string() }];
+    assert_eq!(ErrorCode::Protocol, send(Command::Batch { ops })?.result.unwrap_err().code);
+    Ok(())
+  }
+
+  #[test]
+  fn test_keys_responses() -> Result<()> {
+    let store = MemoryStore::new();
+    let keys: Vec<String> = (0..KEYS_CHUNK_SIZE + 1).map(|i| format!("key-{:05}", i)).collect();
+    for key in &keys {
+      store.set(key.clone(), "value".to_string())?;
+    }
+    let keys_responses = |prefix: &str| -> Result<Vec<Response>> {
+      let mut responses = Vec::new();
+      execute(&store, Command::Keys { prefix: prefix.to_string() }, |response| {
+        responses.push(response);
+        Ok(())
+      })?;
+      Ok(responses)
+    };
+    let responses = keys_responses("key-")?;
+    assert_eq!(2, responses.len());
+    let mut received = Vec::new();
+    for (i, response) in responses.into_iter().enumerate() {
+      let Some(Body::Keys { keys, more }) = response.body else { panic!("没有keys的结果") };
+      assert_eq!(i == 0, more);
+      received.extend(keys);
+    }
+    assert_eq!(keys, received);
+
+    // 没有key时也有一个结束的响应
+    let responses = keys_responses("other")?;
+    assert_eq!(1, responses.len());
+    assert_eq!(Some(Body::Keys { keys: Vec::new(), more: false }), responses[0].body);
+    Ok(())
+  }
+
+  #[test]
+  fn test_invalid_request() -> Result<()> {
+    let addr = start_server()?;
+    let mut tcp_stream = TcpStream::connect(addr)?;
+    tcp_stream.write_all(br#"{"command":{"Unknown":{}}}"#)?;
+    let mut reader = Deserializer::from_reader(BufReader::new(&tcp_stream));
+    let resp = Response::deserialize(&mut reader)?;
+    assert_eq!(ErrorCode::Protocol, resp.result.unwrap_err().code);
+    // 返回错误后关闭连接
+    assert!(Response::deserialize(&mut reader).unwrap_err().is_eof());
+    Ok(())
+  }
+}
@@ -0,0 +1,101 @@
+use std::{io::Result, panic::{self, AssertUnwindSafe}, sync::{mpsc::{self, Receiver, Sender}, Arc, Mutex}, thread::{self, JoinHandle}};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// 固定数量工作线程的线程池
+///
+/// 任务通过channel发给空闲的工作线程，某个任务panic了也不会影响工作线程继续处理后面的任务。
+pub struct ThreadPool {
+  // 发送任务的一端，drop的时候工作线程就会退出
+  sender: Option<Sender<Job>>,
+  // 工作线程
+  workers: Vec<JoinHandle<()>>,
+}
+
+impl ThreadPool {
+  pub fn new(threads: usize) -> Result<ThreadPool> {
+    let (sender, receiver) = mpsc::channel::<Job>();
+    // 所有的工作线程共用一个receiver
+    let receiver = Arc::new(Mutex::new(receiver));
+    let mut workers = Vec::with_capacity(threads);
+    for i in 0..threads.max(1) {
+      let receiver = Arc::clone(&receiver);
+      let worker = thread::Builder::new()
+        .name(format!("kv-worker-{}", i))
+        .spawn(move || run_worker(receiver))?;
+      workers.push(worker);
+    }
+    Ok(ThreadPool { sender: Some(sender), workers })
+  }
+
+  /// 把任务交给线程池执行
+  pub fn spawn<F>(&self, job: F)
+  where
+    F: FnOnce() + Send + 'static,
+  {
+    self.sender
+      .as_ref()
+      .expect("线程池已经关闭！")
+      .send(Box::new(job))
+      .expect("工作线程都已经退出！");
+  }
+}
+
+fn run_worker(receiver: Arc<Mutex<Receiver<Job>>>) {
+  loop {
+    // 拿到任务后马上释放锁，让其它线程也能接任务
+    let job = receiver.lock().unwrap().recv();
+    match job {
+      Ok(job) => {
+        if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+          println!("任务执行异常！");
+        }
+      },
+      // sender已经drop，线程池关闭
+      Err(_) => break,
+    }
+  }
+}
+
+impl Drop for ThreadPool {
+  fn drop(&mut self) {
+    // 先关闭channel，再等所有工作线程把手上的任务做完
+    drop(self.sender.take());
+    for worker in self.workers.drain(..) {
+      let _ = worker.join();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{io::Result, sync::{atomic::{AtomicUsize, Ordering}, Arc}};
+
+  use super::ThreadPool;
+
+  #[test]
+  fn test_spawn() -> Result<()> {
+    let counter = Arc::new(AtomicUsize::new(0));
+    let pool = ThreadPool::new(4)?;
+    for _ in 0..100 {
+      let counter = Arc::clone(&counter);
+      pool.spawn(move || { counter.fetch_add(1, Ordering::SeqCst); });
+    }
+    // drop的时候会等待所有任务执行完
+    drop(pool);
+    assert_eq!(100, counter.load(Ordering::SeqCst));
+    Ok(())
+  }
+
+  #[test]
+  fn test_panic_job() -> Result<()> {
+    let counter = Arc::new(AtomicUsize::new(0));
+    let pool = ThreadPool::new(1)?;
+    pool.spawn(|| panic!("任务异常"));
+    let job_counter = Arc::clone(&counter);
+    pool.spawn(move || { job_counter.fetch_add(1, Ordering::SeqCst); });
+    drop(pool);
+    assert_eq!(1, counter.load(Ordering::SeqCst));
+    Ok(())
+  }
+}